@@ -0,0 +1,533 @@
+use std::{
+    cmp::max,
+    collections::{hash_map::DefaultHasher, BTreeSet, HashMap, HashSet},
+    hash::{Hash, Hasher},
+};
+
+use crate::parser::AST;
+
+/// An upper bound on how a piece of code changes the stack height.
+///
+/// Starting from a height `h`, the height afterwards is at most `max(h + net, floor)`, and the
+/// highest the stack gets along the way is at most `max(h + peak, peak_floor)`. The floors account
+/// for pops from an empty stack, which leave it empty rather than making it shorter.
+#[derive(Clone, Copy)]
+struct Effect {
+    net: i64,
+    floor: i64,
+    peak: i64,
+    peak_floor: i64,
+}
+
+impl Effect {
+    fn none() -> Effect {
+        Effect {
+            net: 0,
+            floor: 0,
+            peak: 0,
+            peak_floor: 0,
+        }
+    }
+
+    fn push(n: i64) -> Effect {
+        Effect {
+            net: n,
+            floor: 0,
+            peak: n,
+            peak_floor: 0,
+        }
+    }
+
+    fn pop(n: i64) -> Effect {
+        Effect {
+            net: -n,
+            floor: 0,
+            peak: 0,
+            peak_floor: 0,
+        }
+    }
+
+    /// Runs `self` followed by `next`.
+    fn then(self, next: Effect) -> Effect {
+        Effect {
+            net: self.net + next.net,
+            floor: max(self.floor + next.net, next.floor),
+            peak: max(self.peak, self.net + next.peak),
+            peak_floor: max(self.peak_floor, max(self.floor + next.peak, next.peak_floor)),
+        }
+    }
+
+    /// Runs either `self` or `other`.
+    fn either(self, other: Effect) -> Effect {
+        Effect {
+            net: max(self.net, other.net),
+            floor: max(self.floor, other.floor),
+            peak: max(self.peak, other.peak),
+            peak_floor: max(self.peak_floor, other.peak_floor),
+        }
+    }
+}
+
+pub enum StackDepth {
+    Bounded(u64),
+    /// The depth can't be bounded because the program recurses through this function.
+    Unbounded(Vec<String>),
+}
+
+/// Conservatively estimates the maximum stack depth reached when running `entry` on an empty stack.
+pub fn max_stack_depth(
+    program: &HashMap<Vec<String>, Vec<AST>>,
+    entry: &Vec<String>,
+) -> StackDepth {
+    let mut effects = HashMap::new();
+    let mut visiting = HashSet::new();
+    match function_effect(program, entry, &mut effects, &mut visiting) {
+        Ok(effect) => StackDepth::Bounded(max(effect.peak, effect.peak_floor) as u64),
+        Err(id) => StackDepth::Unbounded(id),
+    }
+}
+
+fn function_effect(
+    program: &HashMap<Vec<String>, Vec<AST>>,
+    id: &Vec<String>,
+    effects: &mut HashMap<Vec<String>, Effect>,
+    visiting: &mut HashSet<Vec<String>>,
+) -> Result<Effect, Vec<String>> {
+    if let Some(effect) = effects.get(id) {
+        return Ok(*effect);
+    }
+    if !visiting.insert(id.clone()) {
+        return Err(id.clone());
+    }
+
+    let effect = match program.get(id) {
+        Some(asts) => sequence_effect(program, asts, effects, visiting)?,
+        None => Effect::none(),
+    };
+
+    visiting.remove(id);
+    effects.insert(id.clone(), effect);
+    Ok(effect)
+}
+
+fn sequence_effect(
+    program: &HashMap<Vec<String>, Vec<AST>>,
+    asts: &Vec<AST>,
+    effects: &mut HashMap<Vec<String>, Effect>,
+    visiting: &mut HashSet<Vec<String>>,
+) -> Result<Effect, Vec<String>> {
+    let mut effect = Effect::none();
+    for ast in asts {
+        let next = match ast {
+            AST::Left | AST::Right => Effect::push(1),
+            AST::Read => Effect::push(8),
+            AST::Print => Effect::pop(8),
+            AST::Split(l, r) => Effect::pop(1).then(
+                sequence_effect(program, l, effects, visiting)?
+                    .either(sequence_effect(program, r, effects, visiting)?),
+            ),
+            AST::Bracketed(asts) => sequence_effect(program, asts, effects, visiting)?,
+            AST::Id(id) | AST::TailCall(id) => function_effect(program, id, effects, visiting)?,
+        };
+        effect = effect.then(next);
+    }
+    Ok(effect)
+}
+
+/// Lists the distinct functions each function calls, in the order they first appear.
+pub fn call_graph(
+    program: &HashMap<Vec<String>, Vec<AST>>,
+) -> HashMap<Vec<String>, Vec<Vec<String>>> {
+    fn collect(asts: &Vec<AST>, calls: &mut Vec<Vec<String>>) {
+        for ast in asts {
+            match ast {
+                AST::Split(l, r) => {
+                    collect(l, calls);
+                    collect(r, calls);
+                }
+                AST::Bracketed(asts) => collect(asts, calls),
+                AST::Id(id) | AST::TailCall(id) => {
+                    if !calls.contains(id) {
+                        calls.push(id.clone());
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    program
+        .iter()
+        .map(|(name, asts)| {
+            let mut calls = Vec::new();
+            collect(asts, &mut calls);
+            (name.clone(), calls)
+        })
+        .collect()
+}
+
+pub struct CallChain {
+    /// The longest chain of calls from the entry that never re-enters a recursive cycle.
+    pub longest: Vec<Vec<String>>,
+    /// Every reachable function that can end up calling itself.
+    pub recursive: Vec<Vec<String>>,
+}
+
+/// Finds the longest call chain from `entry`, treating calls within a recursive cycle as a single
+/// step.
+pub fn longest_call_chain(
+    program: &HashMap<Vec<String>, Vec<AST>>,
+    entry: &Vec<String>,
+) -> CallChain {
+    let graph = call_graph(program);
+
+    let mut components = Components {
+        graph: &graph,
+        index: HashMap::new(),
+        low: HashMap::new(),
+        stack: Vec::new(),
+        component: HashMap::new(),
+        sizes: Vec::new(),
+    };
+    components.visit(entry);
+
+    let mut recursive: Vec<Vec<String>> = components
+        .component
+        .iter()
+        .filter(|(name, c)| {
+            components.sizes[**c] > 1
+                || graph.get(*name).map_or(false, |calls| calls.contains(name))
+        })
+        .map(|(name, _)| name.clone())
+        .collect();
+    recursive.sort();
+
+    let mut longest = HashMap::new();
+    chain_from(entry, &graph, &components.component, &mut longest);
+
+    CallChain {
+        longest: longest.remove(entry).unwrap_or_default(),
+        recursive,
+    }
+}
+
+fn chain_from(
+    name: &Vec<String>,
+    graph: &HashMap<Vec<String>, Vec<Vec<String>>>,
+    component: &HashMap<Vec<String>, usize>,
+    longest: &mut HashMap<Vec<String>, Vec<Vec<String>>>,
+) {
+    if longest.contains_key(name) {
+        return;
+    }
+
+    let mut best: Vec<Vec<String>> = Vec::new();
+    for callee in graph.get(name).into_iter().flatten() {
+        // calls within the same cycle could go on forever, so don't follow them
+        if component.get(callee) == component.get(name) {
+            continue;
+        }
+        chain_from(callee, graph, component, longest);
+        if longest[callee].len() > best.len() {
+            best = longest[callee].clone();
+        }
+    }
+
+    let mut chain = vec![name.clone()];
+    chain.append(&mut best);
+    longest.insert(name.clone(), chain);
+}
+
+/// Tarjan's algorithm, grouping the functions reachable from a root into strongly connected
+/// components.
+struct Components<'a> {
+    graph: &'a HashMap<Vec<String>, Vec<Vec<String>>>,
+    index: HashMap<Vec<String>, usize>,
+    low: HashMap<Vec<String>, usize>,
+    stack: Vec<Vec<String>>,
+    component: HashMap<Vec<String>, usize>,
+    sizes: Vec<usize>,
+}
+
+impl<'a> Components<'a> {
+    fn visit(&mut self, name: &Vec<String>) {
+        let i = self.index.len();
+        self.index.insert(name.clone(), i);
+        self.low.insert(name.clone(), i);
+        self.stack.push(name.clone());
+
+        for callee in self.graph.get(name).into_iter().flatten() {
+            if !self.index.contains_key(callee) {
+                self.visit(callee);
+                let low = self.low[name].min(self.low[callee]);
+                self.low.insert(name.clone(), low);
+            } else if self.stack.contains(callee) {
+                let low = self.low[name].min(self.index[callee]);
+                self.low.insert(name.clone(), low);
+            }
+        }
+
+        if self.low[name] == self.index[name] {
+            let c = self.sizes.len();
+            let mut size = 0;
+            loop {
+                let member = self.stack.pop().unwrap();
+                self.component.insert(member.clone(), c);
+                size += 1;
+                if &member == name {
+                    break;
+                }
+            }
+            self.sizes.push(size);
+        }
+    }
+}
+
+/// Code that can never run, because it comes after `call` in `function` and `call` never returns.
+pub struct DeadCode {
+    pub function: Vec<String>,
+    pub call: Vec<String>,
+    /// The first node that never runs, counting from 1 through every node of `function` in the
+    /// order `clink ast` lists them.
+    pub node: usize,
+}
+
+/// Finds every sequence with code after a call that never returns.
+pub fn dead_code(program: &HashMap<Vec<String>, Vec<AST>>) -> Vec<DeadCode> {
+    // a function returns if its body can, assuming only the functions found so far can
+    let mut returning = HashSet::new();
+    loop {
+        let before = returning.len();
+        for (name, asts) in program {
+            if !returning.contains(name) && !diverges(asts, &returning) {
+                returning.insert(name.clone());
+            }
+        }
+        if returning.len() == before {
+            break;
+        }
+    }
+
+    // `first` is the number of the first node in `asts`
+    fn find(
+        function: &Vec<String>,
+        asts: &Vec<AST>,
+        first: usize,
+        returning: &HashSet<Vec<String>>,
+        found: &mut Vec<DeadCode>,
+    ) {
+        let mut node = first;
+        for (i, ast) in asts.iter().enumerate() {
+            match ast {
+                AST::Split(l, r) => {
+                    find(function, l, node + 1, returning, found);
+                    find(function, r, node + 1 + count_nodes(l), returning, found);
+                }
+                AST::Bracketed(asts) => find(function, asts, node + 1, returning, found),
+                _ => {}
+            }
+            node += count_nodes(std::slice::from_ref(ast));
+            if i + 1 < asts.len() {
+                if let Some(call) = diverging_call(ast, returning) {
+                    found.push(DeadCode {
+                        function: function.clone(),
+                        call,
+                        node,
+                    });
+                    return;
+                }
+            }
+        }
+    }
+
+    let mut names: Vec<_> = program.keys().collect();
+    names.sort();
+    let mut found = Vec::new();
+    for name in names {
+        find(name, &program[name], 1, &returning, &mut found);
+    }
+    found
+}
+
+/// How many nodes `asts` has, counting those inside splits and brackets as well as the splits and
+/// brackets themselves.
+fn count_nodes(asts: &[AST]) -> usize {
+    asts.iter()
+        .map(|ast| match ast {
+            AST::Split(l, r) => 1 + count_nodes(l) + count_nodes(r),
+            AST::Bracketed(asts) => 1 + count_nodes(asts),
+            _ => 1,
+        })
+        .sum()
+}
+
+fn diverges(asts: &Vec<AST>, returning: &HashSet<Vec<String>>) -> bool {
+    asts.iter()
+        .any(|ast| diverging_call(ast, returning).is_some())
+}
+
+/// The call that stops `ast` from ever returning, if there is one.
+fn diverging_call(ast: &AST, returning: &HashSet<Vec<String>>) -> Option<Vec<String>> {
+    match ast {
+        AST::Id(id) | AST::TailCall(id) if !returning.contains(id) => Some(id.clone()),
+        AST::Bracketed(asts) => asts.iter().find_map(|ast| diverging_call(ast, returning)),
+        AST::Split(l, r) if diverges(r, returning) => {
+            l.iter().find_map(|ast| diverging_call(ast, returning))
+        }
+        _ => None,
+    }
+}
+
+/// The bytes a program can print, as found by `output_alphabet`.
+pub enum OutputAlphabet {
+    Bytes(BTreeSet<u8>),
+    /// Some `#` can print bits read by `@`, so it could print anything.
+    DependsOnInput,
+    /// The program has too many distinct states to follow, like a loop that keeps growing the
+    /// stack.
+    TooComplex,
+}
+
+/// How many nodes `output_alphabet` runs, over every path, before giving up.
+const ALPHABET_STEPS: usize = 100_000;
+
+/// How many bits `output_alphabet` lets the stack hold before giving up.
+const ALPHABET_STACK: usize = 4096;
+
+/// A bit on the stack, either fixed by the program or read from the input.
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+enum Bit {
+    Known(bool),
+    Input,
+}
+
+/// Where a path through the program has got to, and the stack it has there.
+#[derive(Clone)]
+struct Path<'a> {
+    /// The sequences being run, innermost last, with the index of the next node in each.
+    frames: Vec<(&'a [AST], usize)>,
+    stack: Vec<Bit>,
+}
+
+impl<'a> Path<'a> {
+    /// Starts running `asts`, dropping the current sequence first if nothing is left in it, so
+    /// loops through tail calls don't keep adding frames.
+    fn enter(&mut self, asts: &'a [AST]) {
+        if let Some((current, i)) = self.frames.last() {
+            if *i == current.len() {
+                self.frames.pop();
+            }
+        }
+        self.frames.push((asts, 0));
+    }
+
+    /// Pops a bit, or `false` if the stack is empty, as the interpreter does.
+    fn pop(&mut self) -> Bit {
+        self.stack.pop().unwrap_or(Bit::Known(false))
+    }
+}
+
+/// Conservatively finds every byte `#` can print when running `entry` on an empty stack.
+///
+/// Each path through the program is followed with the bits on its stack, splitting in two at a `:`
+/// on a bit read from the input. Paths that reach a call with the same frames and stack as one
+/// seen before are dropped, so loops that come back to the same state end. States are only
+/// remembered by their hash, like `--detect-loops` does in the interpreter.
+pub fn output_alphabet(
+    program: &HashMap<Vec<String>, Vec<AST>>,
+    entry: &Vec<String>,
+) -> OutputAlphabet {
+    let mut bytes = BTreeSet::new();
+    let mut seen = HashSet::new();
+    let mut paths = vec![Path {
+        frames: vec![(program.get(entry).map_or(&[][..], |asts| asts), 0)],
+        stack: Vec::new(),
+    }];
+
+    let mut steps = 0;
+    while let Some(mut path) = paths.pop() {
+        while let Some((asts, i)) = path.frames.last_mut() {
+            if *i == asts.len() {
+                path.frames.pop();
+                continue;
+            }
+            let ast = &asts[*i];
+            *i += 1;
+
+            steps += 1;
+            if steps > ALPHABET_STEPS {
+                return OutputAlphabet::TooComplex;
+            }
+
+            match ast {
+                _ if path.stack.len() > ALPHABET_STACK => return OutputAlphabet::TooComplex,
+                AST::Left => path.stack.push(Bit::Known(true)),
+                AST::Right => path.stack.push(Bit::Known(false)),
+                AST::Read => path.stack.extend([Bit::Input; 8]),
+                AST::Print => {
+                    let mut total: u8 = 0;
+                    for _ in 0..8 {
+                        match path.pop() {
+                            Bit::Known(bit) => total = total * 2 + bit as u8,
+                            Bit::Input => return OutputAlphabet::DependsOnInput,
+                        }
+                    }
+                    bytes.insert(total);
+                }
+                AST::Split(l, r) => match path.pop() {
+                    Bit::Known(true) => path.enter(l),
+                    Bit::Known(false) => path.enter(r),
+                    Bit::Input => {
+                        let mut other = path.clone();
+                        other.enter(r);
+                        paths.push(other);
+                        path.enter(l);
+                    }
+                },
+                AST::Bracketed(asts) => path.enter(asts),
+                AST::Id(id) | AST::TailCall(id) => {
+                    let asts = match program.get(id) {
+                        Some(asts) => asts,
+                        None => continue,
+                    };
+                    path.enter(asts);
+                    let mut hasher = DefaultHasher::new();
+                    for (asts, i) in &path.frames {
+                        (asts.as_ptr(), i).hash(&mut hasher);
+                    }
+                    path.stack.hash(&mut hasher);
+                    if !seen.insert(hasher.finish()) {
+                        break;
+                    }
+                }
+            }
+        }
+    }
+
+    OutputAlphabet::Bytes(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dead_code_is_numbered_like_clink_ast() {
+        let name = |f: &str| vec![f.to_string()];
+        let program = HashMap::from([
+            (name("spin"), vec![AST::TailCall(name("spin"))]),
+            (
+                name("_"),
+                vec![
+                    AST::Split(vec![AST::Right], vec![AST::Id(name("spin")), AST::Print]),
+                    AST::Left,
+                ],
+            ),
+        ]);
+        let found = dead_code(&program);
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].function, name("_"));
+        assert_eq!(found[0].call, name("spin"));
+        assert_eq!(found[0].node, 4);
+    }
+}
@@ -1,33 +1,456 @@
-use std::{collections::HashMap, path::Path, process::Command};
+use std::{
+    collections::{BTreeMap, HashMap},
+    ffi::{c_int, c_void},
+    fmt::Display,
+    fs,
+    path::{Path, PathBuf},
+    process::Command,
+    ptr,
+};
 
 use inkwell::{
+    basic_block::BasicBlock,
     builder::Builder,
     context::Context,
-    targets::{CodeModel, FileType, InitializationConfig, RelocMode, Target, TargetMachine},
-    types::IntType,
-    values::{FunctionValue, GlobalValue},
+    module::{Linkage, Module},
+    passes::{PassManager, PassManagerBuilder},
+    targets::{
+        CodeModel, FileType, InitializationConfig, RelocMode, Target, TargetMachine, TargetTriple,
+    },
+    types::{ArrayType, IntType},
+    values::{BasicValueEnum, FunctionValue, GlobalValue, IntValue, PointerValue},
     AddressSpace, IntPredicate, OptimizationLevel,
 };
 
-use crate::parser::AST;
+use crate::{analysis::call_graph, interpreter::Frame, parser::AST};
 
+/// The number of bits the stack has room for before it first has to grow.
 const ARRAY_SIZE: u32 = 1024;
 
-pub fn compile(module_name: &str, funcs: HashMap<Vec<String>, Vec<AST>>, entry: Vec<String>) {
+#[derive(Debug)]
+pub enum CompileError {
+    /// A module failed to verify, or to generate code, at an optimisation level from `verify_at`.
+    InvalidModule(u32, String),
+    /// The linker ran but didn't write the binary, given by its path.
+    LinkProducedNoOutput(String),
+    /// A file, and why it couldn't be written.
+    CannotWrite(String, String),
+    /// `ar` failed to build the archive, with whatever it printed.
+    ArchiveFailed(String),
+    /// The compiler used for linking couldn't be run, given by its name and why.
+    LinkerNotFound(String, String),
+    /// The linker exited with an error, with whatever it printed and the command to retry by hand.
+    LinkFailed(String, String),
+    /// LLVM couldn't set up a JIT for this machine.
+    JitUnavailable(String),
+    /// A file given to run as bitcode, and why it couldn't be loaded.
+    InvalidBitcode(String, String),
+    /// A target triple LLVM doesn't know how to build for, and why.
+    UnknownTarget(String, String),
+}
+
+impl Display for CompileError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CompileError::InvalidModule(level, e) => {
+                write!(f, "ERROR: module doesn't verify at O{}: {}", level, e)
+            }
+            CompileError::LinkProducedNoOutput(path) => {
+                writeln!(f, "ERROR: linking didn't produce `{}`", path)?;
+                write!(f, "HINT:  check the linker, and any flags it was given")
+            }
+            CompileError::CannotWrite(path, e) => {
+                write!(f, "ERROR: cannot write `{}`: {}", path, e)
+            }
+            CompileError::ArchiveFailed(e) => write!(f, "ERROR: cannot build archive: {}", e),
+            CompileError::LinkerNotFound(cc, e) => {
+                writeln!(f, "ERROR: cannot run `{}` to link: {}", cc, e)?;
+                write!(
+                    f,
+                    "HINT:  install clang and make sure it's on your PATH, or pick another compiler with `--cc`"
+                )
+            }
+            CompileError::JitUnavailable(e) => write!(f, "ERROR: cannot JIT compile: {}", e),
+            CompileError::InvalidBitcode(path, e) => {
+                writeln!(f, "ERROR: cannot load bitcode from `{}`: {}", path, e)?;
+                write!(f, "HINT:  build it with `clink build --emit-bc`")
+            }
+            CompileError::UnknownTarget(triple, e) => {
+                writeln!(f, "ERROR: cannot build for target `{}`: {}", triple, e)?;
+                write!(
+                    f,
+                    "HINT:  targets are triples, like `aarch64-unknown-linux-gnu`"
+                )
+            }
+            CompileError::LinkFailed(e, command) => {
+                writeln!(f, "ERROR: linking failed: {}", e)?;
+                write!(f, "HINT:  link manually with `{}`", command)
+            }
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct CompileOptions {
+    /// Emit a bounds check before every stack access, aborting with a message on failure.
+    pub debug_stack: bool,
+    /// When non-empty, `main` dispatches to one of these entry functions by the name the binary was
+    /// invoked as (or its first argument) instead of always calling the entry.
+    pub multicall: Vec<(String, Vec<String>)>,
+    /// Emit LLVM bitcode and link it with `-flto` instead of emitting a native object.
+    pub lto: bool,
+    /// The byte `@` reads once the input is exhausted.
+    pub eof_byte: u8,
+    /// Skip the checks that keep the index inside the stack, which pop `?`s from an empty stack and
+    /// grow the stack when it's full, or stop the program with an error if it can't grow. Only
+    /// safe for programs known never to pop an empty stack or push past its starting size.
+    pub no_bounds_check: bool,
+    /// The most bits the stack can hold, or no limit if unset, so it grows as far as memory allows
+    /// like the interpreter's.
+    pub stack_size: Option<u32>,
+    /// The compiler used for linking, or `clang` from the PATH if unset.
+    pub cc: Option<String>,
+    /// Build each top-level package into its own object, and link them all together.
+    pub split_modules: bool,
+    /// A hash of the sources to embed in the binary as the string `clink_source_hash`.
+    pub source_hash: Option<String>,
+    /// Write a C header declaring every compiled function to this file.
+    pub header: Option<String>,
+    /// Push the program's arguments onto the stack before running the entry, the same way `@`
+    /// pushes bytes.
+    pub push_args: bool,
+    /// Print each function's clink name next to the symbol it was compiled to.
+    pub dump_symbols: bool,
+    /// Build a static library `lib<name>.a` of every function, with no `main`, instead of a
+    /// binary.
+    pub lib: bool,
+    /// Count every function's calls and the cycles spent in it, including in the functions it
+    /// calls, and print them to stderr when the program exits. Not supported with
+    /// `split_modules`.
+    pub instrument: bool,
+    /// Optimisation levels, from 0 to 3, to optimise and generate code for each module at before
+    /// writing it, failing if the module doesn't verify at any of them.
+    pub verify_at: Vec<u32>,
+    /// The optimisation level, from 0 to 3, to generate code at, or 3 if unset.
+    pub opt_level: Option<u32>,
+    /// Also write each module's textual IR next to its object, as `<name>.ll`.
+    pub emit_ir: bool,
+    /// Also write each module's native assembly next to its object, as `<name>.s`.
+    pub emit_asm: bool,
+    /// Also write the module as bitcode to `<name>.bc`, for running later without parsing or
+    /// building it again. Not supported with `split_modules` or `lib`.
+    pub emit_bc: bool,
+    /// Where to write the binary, or the archive with `lib`, instead of `<module_name>` or
+    /// `lib<module_name>.a`. The objects are written to the same directory.
+    pub output: Option<String>,
+    /// The triple to build for, with a generic CPU, or the host machine if unset.
+    pub target: Option<String>,
+    /// Wrap everything the program prints in a frame, closed when it exits.
+    pub frame: Option<Frame>,
+    /// Export the entry as `unsigned char clink_entry(unsigned char input)`, which runs it on an
+    /// empty stack with `input` as the only byte for `@` to read, and returns the last byte `#`
+    /// printed, or 0 if none was. Reads after the first get `eof_byte`. Nothing goes through
+    /// libc's I/O, and the stack is shared, so calls mustn't overlap. Only used with `lib`.
+    pub embed_abi: bool,
+}
+
+/// A program built into a module in memory, before anything is written.
+struct Built<'a> {
+    module: Module<'a>,
+    func_defs: HashMap<Vec<String>, FunctionValue<'a>>,
+    /// With `split_modules`, the functions left to build in each top-level package's module.
+    packages: BTreeMap<String, Vec<(Vec<String>, Vec<AST>)>>,
+}
+
+/// The functions a module's constructor can register with `atexit`, in the order it does.
+const AT_EXIT: [&str; 2] = ["clink_profile_report", "clink_frame_close"];
+
+pub fn compile(
+    module_name: &str,
+    funcs: HashMap<Vec<String>, Vec<AST>>,
+    entry: Vec<String>,
+    options: &CompileOptions,
+) -> Result<(), CompileError> {
     let context = Context::create();
+    let Built {
+        module,
+        func_defs,
+        packages,
+        ..
+    } = build_module(&context, module_name, funcs, entry, options)?;
+
+    Target::initialize_all(&InitializationConfig::default());
+    let (target_triple, cpu, features) = match &options.target {
+        // nothing is known about another machine, so don't assume anything beyond its triple
+        Some(triple) => (TargetTriple::create(triple), String::new(), String::new()),
+        // use the host machine as the compilation target
+        None => (
+            TargetMachine::get_default_triple(),
+            TargetMachine::get_host_cpu_name().to_string(),
+            TargetMachine::get_host_cpu_features().to_string(),
+        ),
+    };
+    let unknown_target = |e: String| {
+        CompileError::UnknownTarget(target_triple.as_str().to_string_lossy().into_owned(), e)
+    };
+
+    // make a target from the triple
+    let target = Target::from_triple(&target_triple).map_err(|e| unknown_target(e.to_string()))?;
+
+    let target_machine = target
+        .create_target_machine(
+            &target_triple,
+            &cpu,
+            &features,
+            optimization_level(options.opt_level.unwrap_or(3)),
+            RelocMode::Default,
+            CodeModel::Default,
+        )
+        .ok_or_else(|| unknown_target("cannot create a target machine".to_string()))?;
+    let verify_machines = options
+        .verify_at
+        .iter()
+        .map(|&level| {
+            target
+                .create_target_machine(
+                    &target_triple,
+                    &cpu,
+                    &features,
+                    optimization_level(level),
+                    RelocMode::Default,
+                    CodeModel::Default,
+                )
+                .map(|machine| (level, machine))
+                .ok_or_else(|| unknown_target("cannot create a target machine".to_string()))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let extension = if options.lto { ".bc" } else { ".o" };
+    let write = |module: &Module, output_filename: &Path| -> Result<(), CompileError> {
+        module.set_triple(&target_triple);
+        module.set_data_layout(&target_machine.get_target_data().get_data_layout());
+
+        if options.emit_ir {
+            let ir = output_filename.with_extension("ll");
+            module
+                .print_to_file(&ir)
+                .map_err(|e| CompileError::CannotWrite(ir.display().to_string(), e.to_string()))?;
+        }
+
+        for (level, machine) in &verify_machines {
+            verify_at(module, machine, *level)?;
+        }
+
+        if options.emit_asm {
+            // generating code can change the module, so leave the one being written alone
+            let asm = output_filename.with_extension("s");
+            target_machine
+                .write_to_file(&module.clone(), FileType::Assembly, &asm)
+                .map_err(|e| CompileError::CannotWrite(asm.display().to_string(), e.to_string()))?;
+        }
+
+        let cannot_write =
+            |e: String| CompileError::CannotWrite(output_filename.display().to_string(), e);
+        if options.lto {
+            // leave optimisation across functions to the linker
+            if !module.write_bitcode_to_path(output_filename) {
+                return Err(cannot_write("LLVM couldn't write the bitcode".to_string()));
+            }
+        } else {
+            target_machine
+                .write_to_file(module, FileType::Object, output_filename)
+                .map_err(|e| cannot_write(e.to_string()))?;
+        }
+        Ok(())
+    };
+
+    let binary = PathBuf::from(match &options.output {
+        Some(output) => output.clone(),
+        None if options.lib => format!("lib{}.a", module_name),
+        None => module_name.to_string(),
+    });
+    let dir = binary.parent().unwrap_or(Path::new("")).to_path_buf();
+
+    let mut output_filenames = vec![dir.join(module_name.to_string() + extension)];
+    write(&module, &output_filenames[0])?;
+    let bitcode = dir.join(module_name.to_string() + ".bc");
+    if options.emit_bc {
+        // with `lto`, this is the same file the object was written to, and the same bitcode
+        if !module.write_bitcode_to_path(&bitcode) {
+            return Err(CompileError::CannotWrite(
+                bitcode.display().to_string(),
+                "LLVM couldn't write the bitcode".to_string(),
+            ));
+        }
+    }
+
+    let symbols: HashMap<Vec<String>, String> = func_defs
+        .iter()
+        .map(|(name, function)| {
+            let symbol = function.get_name().to_str().unwrap().to_string();
+            (name.clone(), symbol)
+        })
+        .collect();
+    for (package, funcs) in packages {
+        let package_module = build_package_module(
+            &context,
+            &format!("{}.{}", module_name, package),
+            &symbols,
+            funcs,
+            options,
+        );
+        let output_filename = dir.join(format!("{}.{}{}", module_name, package, extension));
+        write(&package_module, &output_filename)?;
+        output_filenames.push(output_filename);
+    }
+
+    if options.lib {
+        return archive(&binary, &output_filenames);
+    }
+
+    let cc = options.cc.as_deref().unwrap_or("clang");
+    let mut cmd = Command::new(cc);
+    if options.lto {
+        cmd.arg("-flto");
+    }
+    // clang can link for any target it's told about, but other compilers are for one target only
+    let target_flag = match (&options.target, &options.cc) {
+        (Some(triple), None) => format!("--target={}", triple),
+        _ => String::new(),
+    };
+    if !target_flag.is_empty() {
+        cmd.arg(&target_flag);
+    }
+    let objects: Vec<&str> = output_filenames
+        .iter()
+        .map(|f| f.to_str().unwrap())
+        .collect();
+    // a binary left over from an earlier build would hide a link that wrote nothing
+    if binary.exists() {
+        let _ = fs::remove_file(&binary);
+    }
+    let output = cmd
+        .args(&output_filenames)
+        .arg("-o")
+        .arg(&binary)
+        .output()
+        .map_err(|e| CompileError::LinkerNotFound(cc.to_string(), e.to_string()))?;
+    if !output.status.success() {
+        // the objects are kept, so the link can be retried by hand
+        return Err(CompileError::LinkFailed(
+            String::from_utf8_lossy(&output.stderr).trim().to_string(),
+            format!(
+                "{} {}{}{}{} -o {}",
+                cc,
+                if options.lto { "-flto " } else { "" },
+                target_flag,
+                if target_flag.is_empty() { "" } else { " " },
+                objects.join(" "),
+                binary.display()
+            ),
+        ));
+    }
+    if !binary.exists() {
+        return Err(CompileError::LinkProducedNoOutput(
+            binary.display().to_string(),
+        ));
+    }
+    for object in &output_filenames {
+        if options.emit_bc && object == &bitcode {
+            continue;
+        }
+        let _ = fs::remove_file(object);
+    }
+    Ok(())
+}
+
+extern "C" {
+    fn fflush(stream: *mut c_void) -> c_int;
+}
+
+/// Builds the program in memory and runs it straight away with LLVM's JIT, returning the status
+/// its `main` exited with. `args` are passed to `main` after a program name, so they're pushed
+/// onto the stack with `push_args`. It reads and prints through this process's own stdin and
+/// stdout.
+pub fn run_jit(
+    funcs: HashMap<Vec<String>, Vec<AST>>,
+    entry: Vec<String>,
+    args: &[String],
+    options: &CompileOptions,
+) -> Result<i32, CompileError> {
+    let context = Context::create();
+    let Built { module, .. } = build_module(&context, "clink", funcs, entry, options)?;
+    run_module(&module, args, options.opt_level.unwrap_or(3))
+}
+
+/// Like `run_jit`, on a program built earlier with `emit_bc`.
+pub fn run_bitcode(path: &Path, args: &[String], opt_level: u32) -> Result<i32, CompileError> {
+    let context = Context::create();
+    let module = Module::parse_bitcode_from_path(path, &context)
+        .map_err(|e| CompileError::InvalidBitcode(path.display().to_string(), e.to_string()))?;
+    run_module(&module, args, opt_level)
+}
+
+fn run_module(module: &Module, args: &[String], opt_level: u32) -> Result<i32, CompileError> {
+    Target::initialize_native(&InitializationConfig::default())
+        .map_err(CompileError::JitUnavailable)?;
+    let engine = module
+        .create_jit_execution_engine(optimization_level(opt_level))
+        .map_err(|e| CompileError::JitUnavailable(e.to_string()))?;
+    let main = engine
+        .get_function_value("main")
+        .map_err(|e| CompileError::JitUnavailable(format!("{:?}", e)))?;
+
+    let argv: Vec<&str> = ["clink"]
+        .into_iter()
+        .chain(args.iter().map(|a| a.as_str()))
+        .collect();
+    let status = unsafe {
+        let status = engine.run_function_as_main(main, &argv);
+        // the constructor registering these never ran, and the code they'd call is gone by the
+        // time this process exits, so run them now, in the order `atexit` would
+        for name in AT_EXIT.iter().rev() {
+            if let Some(handler) = module.get_function(name) {
+                engine.run_function(handler, &[]);
+            }
+        }
+        // the program printed through C's buffered stdout, which only flushes itself at exit
+        fflush(ptr::null_mut());
+        status
+    };
+    Ok(status)
+}
+
+fn build_module<'a>(
+    context: &'a Context,
+    module_name: &str,
+    funcs: HashMap<Vec<String>, Vec<AST>>,
+    entry: Vec<String>,
+    options: &CompileOptions,
+) -> Result<Built<'a>, CompileError> {
     let module = context.create_module(module_name);
     let builder = context.create_builder();
 
     // initialise types and globals
 
     let bool_type = context.bool_type();
-    let stack_type = bool_type.array_type(ARRAY_SIZE);
-
-    let stack = module.add_global(stack_type, Some(AddressSpace::default()), "stack");
-    stack.set_initializer(&stack_type.const_zero());
-
     let i64_type = context.i64_type();
     let i32_type = context.i32_type();
+
+    // the stack starts out in a buffer of its own, so it's usable before anything has run, and
+    // only moves to the heap if it has to grow past it. With a limit, it starts out that big, plus
+    // the one bit a push writes before `reserve` finds it's one too many.
+    let initial_size = options.stack_size.map_or(ARRAY_SIZE, |size| size + 1);
+    let initial_type = bool_type.array_type(initial_size);
+    let initial = module.add_global(initial_type, Some(AddressSpace::default()), "stack_initial");
+    initial.set_initializer(&initial_type.const_zero());
+
+    let bool_ptr_type = bool_type.ptr_type(AddressSpace::default());
+    let stack = module.add_global(bool_ptr_type, Some(AddressSpace::default()), "stack");
+    stack.set_initializer(&initial.as_pointer_value());
+    let capacity = module.add_global(i64_type, Some(AddressSpace::default()), "capacity");
+    capacity.set_initializer(&i64_type.const_int(initial_size.into(), false));
+
     let index = module.add_global(i64_type, Some(AddressSpace::default()), "index");
     index.set_initializer(&i64_type.const_zero());
 
@@ -48,8 +471,52 @@ pub fn compile(module_name: &str, funcs: HashMap<Vec<String>, Vec<AST>>, entry:
         .get_function("getchar")
         .unwrap_or(module.add_function("getchar", gc_fn_type, None));
 
+    let str_type = context.i8_type().ptr_type(AddressSpace::default());
+
+    let wr_fn_type = i32_type.fn_type(&[i32_type.into(), str_type.into(), i64_type.into()], false);
+    let wr_fn_val = module
+        .get_function("write")
+        .unwrap_or(module.add_function("write", wr_fn_type, None));
+
+    let ab_fn_val = module
+        .get_function("abort")
+        .unwrap_or(module.add_function("abort", fn_type, None));
+
+    let ra_fn_val = module.add_function(
+        "realloc",
+        str_type.fn_type(&[str_type.into(), i64_type.into()], false),
+        None,
+    );
+
+    let mc_fn_val = module.add_function(
+        "memcpy",
+        str_type.fn_type(&[str_type.into(), str_type.into(), i64_type.into()], false),
+        None,
+    );
+
     // internal functions
 
+    let trap_func = module.add_function(
+        "trap",
+        void_type.fn_type(&[str_type.into(), i64_type.into()], false),
+        None,
+    );
+    {
+        let basic_block = context.append_basic_block(trap_func, "entry");
+        builder.position_at_end(basic_block);
+
+        // write the message to stderr and abort
+        let msg = trap_func.get_nth_param(0).unwrap();
+        let len = trap_func.get_nth_param(1).unwrap();
+        builder.build_call(
+            wr_fn_val,
+            &[i32_type.const_int(2, false).into(), msg.into(), len.into()],
+            "",
+        );
+        builder.build_call(ab_fn_val, &[], "");
+        builder.build_unreachable();
+    }
+
     let dec_func = module.add_function("decri", fn_type, None);
     {
         let basic_block = context.append_basic_block(dec_func, "entry");
@@ -60,26 +527,56 @@ pub fn compile(module_name: &str, funcs: HashMap<Vec<String>, Vec<AST>>, entry:
         // dec i_p
         let i_ov = builder.build_load(i64_type, i_p, "").into_int_value();
 
-        let if_z = context.append_basic_block(dec_func, "");
-        let el = context.append_basic_block(dec_func, "");
+        if !options.no_bounds_check {
+            let if_z = context.append_basic_block(dec_func, "");
+            let el = context.append_basic_block(dec_func, "");
 
-        builder.build_conditional_branch(
-            builder.build_int_compare(IntPredicate::EQ, i_ov, i64_type.const_zero(), ""),
-            if_z,
-            el,
-        );
+            builder.build_conditional_branch(
+                builder.build_int_compare(IntPredicate::EQ, i_ov, i64_type.const_zero(), ""),
+                if_z,
+                el,
+            );
 
-        builder.position_at_end(if_z);
+            builder.position_at_end(if_z);
 
-        builder.build_return(None);
+            builder.build_return(None);
 
-        builder.position_at_end(el);
+            builder.position_at_end(el);
+        }
 
         let i_v = builder.build_int_sub(i_ov, i64_type.const_int(1, false), "");
         builder.build_store(i_p, i_v);
         builder.build_return(None);
     }
 
+    // makes room for a bit at the index given, growing the stack if it has to
+    let reserve_func = module.add_function(
+        "reserve",
+        void_type.fn_type(&[i64_type.into()], false),
+        None,
+    );
+    {
+        let basic_block = context.append_basic_block(reserve_func, "entry");
+        builder.position_at_end(basic_block);
+
+        let i_nv = reserve_func.get_nth_param(0).unwrap().into_int_value();
+        match options.stack_size {
+            Some(stack_size) => {
+                build_overflow_check(context, &builder, reserve_func, trap_func, i_nv, stack_size)
+            }
+            None => build_grow(
+                context,
+                &builder,
+                reserve_func,
+                trap_func,
+                i_nv,
+                (stack, capacity, initial),
+                (ra_fn_val, mc_fn_val),
+            ),
+        }
+        builder.build_return(None);
+    }
+
     let inc_func = module.add_function("incri", fn_type, None);
     {
         let basic_block = context.append_basic_block(inc_func, "entry");
@@ -91,69 +588,122 @@ pub fn compile(module_name: &str, funcs: HashMap<Vec<String>, Vec<AST>>, entry:
 
         let i_nv = builder.build_int_add(i_v, i64_type.const_int(1, false), "");
 
-        let if_l = context.append_basic_block(inc_func, "");
-        let el = context.append_basic_block(inc_func, "");
-
-        builder.build_conditional_branch(
-            builder.build_int_compare(
-                IntPredicate::UGE,
-                i_nv,
-                i64_type.const_int(ARRAY_SIZE.into(), false),
-                "",
-            ),
-            if_l,
-            el,
-        );
-
-        builder.position_at_end(if_l);
-        // PANIC!!!!!!!
-        builder.build_return(None);
-
-        builder.position_at_end(el);
+        if !options.no_bounds_check {
+            builder.build_call(reserve_func, &[i_nv.into()], "");
+        }
         builder.build_store(i_p, i_nv);
         builder.build_return(None);
     }
 
+    // with `embed_abi`, the byte passed in, or -1 once it has been read, and the byte to return
+    let embed_io = if options.embed_abi {
+        let input = module.add_global(i32_type, None, "clink_embed_input");
+        input.set_initializer(&i32_type.const_all_ones());
+        let output = module.add_global(i32_type, None, "clink_embed_output");
+        output.set_initializer(&i32_type.const_zero());
+        Some((input, output))
+    } else {
+        None
+    };
+
+    let (put_func, close_frame) = match options.frame {
+        Some(frame) => {
+            let (put, close) =
+                build_frame(&context, &module, &builder, frame, pc_fn_val, ab_fn_val);
+            (put, Some(close))
+        }
+        None => (pc_fn_val, None),
+    };
+
     let print_func = module.add_function("print", fn_type, None);
     {
         let basic_block = context.append_basic_block(print_func, "entry");
         builder.position_at_end(basic_block);
 
-        let s_p = stack.as_pointer_value();
+        let s_p = build_stack_base(&builder, &stack, bool_type);
+        let i_p = index.as_pointer_value();
+        let i_ov = builder.build_load(i64_type, i_p, "").into_int_value();
         let mut acc = chr_type.const_int(0, false);
 
-        for _ in 0..8 {
-            builder.build_call(dec_func, &[], "");
-            let i_p = index.as_pointer_value();
-            let i_v = builder.build_load(i64_type, i_p, "").into_int_value();
+        // pop 8 times without going below the bottom of the stack, like decri. The first bit
+        // popped is the most significant, the same as in the interpreter.
+        let popped = |n: u64| {
+            let n = i64_type.const_int(n, false);
+            if options.no_bounds_check {
+                return builder.build_int_sub(i_ov, n, "");
+            }
+            builder
+                .build_select(
+                    builder.build_int_compare(IntPredicate::UGE, i_ov, n, ""),
+                    builder.build_int_sub(i_ov, n, ""),
+                    i64_type.const_zero(),
+                    "",
+                )
+                .into_int_value()
+        };
+
+        for n in 1..=8 {
+            let i_v = popped(n);
 
             unsafe {
                 let x_p = builder.build_in_bounds_gep(bool_type, s_p, &[i_v], "");
-                let this_bit = builder
-                    .build_load(bool_type, x_p, "")
-                    .into_int_value()
-                    .const_cast(chr_type, false);
+                let mut bit = builder.build_load(bool_type, x_p, "").into_int_value();
+                if !options.no_bounds_check {
+                    // below the bottom, the slot there is left over from an earlier push, but
+                    // popping an empty stack gives `?`
+                    bit = builder
+                        .build_select(
+                            builder.build_int_compare(
+                                IntPredicate::UGE,
+                                i_ov,
+                                i64_type.const_int(n, false),
+                                "",
+                            ),
+                            bit,
+                            bool_type.const_zero(),
+                            "",
+                        )
+                        .into_int_value();
+                }
+                let this_bit = builder.build_int_z_extend(bit, chr_type, "");
 
                 acc = builder.build_int_mul(acc, chr_type.const_int(2, false), "");
                 acc = builder.build_int_add(acc, this_bit, "");
             }
         }
 
-        builder.build_call(pc_fn_val, &[acc.into()], "");
+        builder.build_store(i_p, popped(8));
+        match embed_io {
+            Some((_, output)) => {
+                builder.build_store(output.as_pointer_value(), acc);
+            }
+            None => {
+                builder.build_call(put_func, &[acc.into()], "");
+            }
+        }
         builder.build_return(None);
     }
 
-    let read_func = module.add_function("read", fn_type, None);
+    let push_byte_func =
+        module.add_function("push_byte", void_type.fn_type(&[chr_type.into()], false), None);
     {
-        let basic_block = context.append_basic_block(read_func, "entry");
+        let basic_block = context.append_basic_block(push_byte_func, "entry");
         builder.position_at_end(basic_block);
 
-        let s_p = stack.as_pointer_value();
-        let mut acc = builder.build_call(gc_fn_val, &[], "").try_as_basic_value().unwrap_left().into_int_value();
+        let i_p = index.as_pointer_value();
+        let i_ov = builder.build_load(i64_type, i_p, "").into_int_value();
+        let mut acc = push_byte_func.get_nth_param(0).unwrap().into_int_value();
 
-        for _ in 0..8 {
-            let i_p = index.as_pointer_value();
-            let i_v = builder.build_load(i64_type, i_p, "").into_int_value();
+        // least significant bit first, like the interpreter's `push_byte`
+        let pushed = |n: u64| builder.build_int_add(i_ov, i64_type.const_int(n, false), "");
+        if !options.no_bounds_check {
+            builder.build_call(reserve_func, &[pushed(8).into()], "");
+        }
+        // only once there's room, as making it can move the stack
+        let s_p = build_stack_base(&builder, &stack, bool_type);
+
+        for n in 0..8 {
+            let i_v = pushed(n);
 
             unsafe {
                 let x_p = builder.build_in_bounds_gep(bool_type, s_p, &[i_v], "");
@@ -162,29 +712,173 @@ pub fn compile(module_name: &str, funcs: HashMap<Vec<String>, Vec<AST>>, entry:
             }
 
             acc = builder.build_right_shift(acc, i32_type.const_int(1, false), false, "");
-            builder.build_call(inc_func, &[], "");
         }
 
+        builder.build_store(i_p, pushed(8));
+        builder.build_return(None);
+    }
+
+    let read_func = module.add_function("read", fn_type, None);
+    {
+        let basic_block = context.append_basic_block(read_func, "entry");
+        builder.position_at_end(basic_block);
+
+        let c = match embed_io {
+            Some((input, _)) => {
+                let input = input.as_pointer_value();
+                let c = builder.build_load(i32_type, input, "").into_int_value();
+                builder.build_store(input, i32_type.const_all_ones());
+                c
+            }
+            None => builder
+                .build_call(gc_fn_val, &[], "")
+                .try_as_basic_value()
+                .unwrap_left()
+                .into_int_value(),
+        };
+
+        // getchar returns EOF (-1) once the input is exhausted, and so does the embedded input
+        let c = builder.build_select(
+            builder.build_int_compare(IntPredicate::SLT, c, i32_type.const_zero(), ""),
+            i32_type.const_int(options.eof_byte.into(), false),
+            c,
+            "",
+        );
+        builder.build_call(push_byte_func, &[c.into()], "");
         builder.build_return(None);
     }
 
+    if let Some(hash) = &options.source_hash {
+        let value = context.const_string(hash.as_bytes(), true);
+        let global = module.add_global(value.get_type(), None, "clink_source_hash");
+        global.set_initializer(&value);
+        global.set_constant(true);
+    }
+
     let mut entry_func = None;
     let mut func_defs = HashMap::new();
 
+    // when nothing else calls the entry, its body can be `main` itself instead of a call from it
+    let inline_entry = options.multicall.is_empty()
+        && !options.lib
+        && !options.push_args
+        && !options.split_modules
+        && !call_graph(&funcs)
+            .values()
+            .any(|calls| calls.contains(&entry));
+
+    // `main` returns the exit status, so even an empty program exits cleanly
+    let main_fn_type = i32_type.fn_type(&[], false);
     for (name, _) in &funcs {
-        let function = module.add_function(name.join("_").as_str(), fn_type, None);
+        let function = if inline_entry && name == &entry {
+            module.add_function("main", main_fn_type, None)
+        } else {
+            module.add_function(name.join("_").as_str(), fn_type, None)
+        };
         func_defs.insert(name.clone(), function);
     }
 
+    let profile = if options.instrument {
+        Some(build_profile(&context, &module, &builder, &func_defs))
+    } else {
+        None
+    };
+
+    // in the same order as `AT_EXIT`
+    let at_exit: Vec<_> = profile
+        .as_ref()
+        .map(|profile| profile.report)
+        .into_iter()
+        .chain(close_frame)
+        .collect();
+    if !at_exit.is_empty() {
+        build_at_exit(&context, &module, &builder, &at_exit);
+    }
+
+    if options.dump_symbols {
+        let mut names: Vec<_> = func_defs.keys().collect();
+        names.sort();
+        for name in names {
+            // LLVM renames clashing symbols, so ask it for the name it actually used
+            println!(
+                "{} {}",
+                name.join("."),
+                func_defs[name].get_name().to_str().unwrap()
+            );
+        }
+    }
+
+    if let Some(header) = &options.header {
+        let guard: String = Path::new(header)
+            .file_name()
+            .and_then(|name| name.to_str())
+            .unwrap_or("clink")
+            .chars()
+            .map(|c| {
+                if c.is_ascii_alphanumeric() {
+                    c.to_ascii_uppercase()
+                } else {
+                    '_'
+                }
+            })
+            .collect();
+        let mut text = format!("#ifndef {}\n#define {}\n", guard, guard);
+        let mut names: Vec<_> = func_defs.keys().collect();
+        names.sort();
+        for name in names {
+            let symbol = func_defs[name].get_name().to_str().unwrap();
+            // the entry can be built as `main`, which C already knows about
+            if symbol != "main" {
+                text += &format!(
+                    "\n/* {} */\nextern void {}(void);\n",
+                    name.join("."),
+                    symbol
+                );
+            }
+        }
+        if options.embed_abi {
+            text += &format!(
+                "\n/* {}, on a single input byte */\nunsigned char clink_entry(unsigned char input);\n",
+                entry.join(".")
+            );
+        }
+        text += "\n#endif\n";
+        fs::write(header, text)
+            .map_err(|e| CompileError::CannotWrite(header.clone(), e.to_string()))?;
+    }
+
+    let mut packages: BTreeMap<String, Vec<(Vec<String>, Vec<AST>)>> = BTreeMap::new();
+
     for (name, asts) in funcs {
         let function = func_defs[&name];
-        let basic_block = context.append_basic_block(function, "entry");
-        builder.position_at_end(basic_block);
 
         if name == entry {
             entry_func = Some(function)
         }
 
+        if options.split_modules {
+            // left as a declaration here, and built in its package's module below
+            packages
+                .entry(name[0].clone())
+                .or_default()
+                .push((name, asts));
+            continue;
+        }
+
+        let basic_block = context.append_basic_block(function, "entry");
+        builder.position_at_end(basic_block);
+
+        let function_name = name.join(".");
+        let started = profile
+            .as_ref()
+            .map(|profile| profile.build_entry(&builder, &name));
+        // profiling has to see every call end, and `main` returns something else
+        let top = if profile.is_none() && !(inline_entry && name == entry) {
+            Some(build_top(&context, &builder, function))
+        } else {
+            None
+        };
+
         build_ast(
             asts,
             &Env {
@@ -200,50 +894,693 @@ pub fn compile(module_name: &str, funcs: HashMap<Vec<String>, Vec<AST>>, entry:
                 inc_func: inc_func,
                 func_defs: &func_defs,
                 read_func: read_func,
+                trap_func: trap_func,
+                debug_stack: options.debug_stack,
+                stack_size: options.stack_size,
+                function_name: &function_name,
+                top,
+            },
+            true,
+        );
+
+        if let (Some(profile), Some(started)) = (&profile, started) {
+            profile.build_exit(&builder, &name, started);
+        }
+        if inline_entry && name == entry {
+            builder.build_return(Some(&i32_type.const_zero()));
+        } else {
+            builder.build_return(None);
+        }
+    }
+
+    if inline_entry {
+        // the entry was already built as `main`
+    } else if options.lib {
+        // whatever links the library brings its own `main`
+        if let Some((input, output)) = embed_io {
+            let i8_type = context.i8_type();
+            let function = module.add_function(
+                "clink_entry",
+                i8_type.fn_type(&[i8_type.into()], false),
+                None,
+            );
+            let basic_block = context.append_basic_block(function, "entry");
+            builder.position_at_end(basic_block);
+            let byte = function.get_nth_param(0).unwrap().into_int_value();
+            builder.build_store(index.as_pointer_value(), i64_type.const_zero());
+            builder.build_store(
+                input.as_pointer_value(),
+                builder.build_int_z_extend(byte, i32_type, ""),
+            );
+            builder.build_store(output.as_pointer_value(), i32_type.const_zero());
+            builder.build_call(entry_func.unwrap(), &[], "");
+            let output = builder
+                .build_load(i32_type, output.as_pointer_value(), "")
+                .into_int_value();
+            builder.build_return(Some(&builder.build_int_truncate(output, i8_type, "")));
+        }
+    } else if options.push_args {
+        let argv_type = str_type.ptr_type(AddressSpace::default());
+        let main_type = i32_type.fn_type(&[i32_type.into(), argv_type.into()], false);
+        let function = module.add_function("main", main_type, None);
+
+        let basic_block = context.append_basic_block(function, "entry");
+        let args = context.append_basic_block(function, "");
+        let arg = context.append_basic_block(function, "");
+        let separate = context.append_basic_block(function, "");
+        let chars = context.append_basic_block(function, "");
+        let push = context.append_basic_block(function, "");
+        let next = context.append_basic_block(function, "");
+        let run = context.append_basic_block(function, "");
+
+        builder.position_at_end(basic_block);
+        let argc = function.get_nth_param(0).unwrap().into_int_value();
+        let argv = function.get_nth_param(1).unwrap().into_pointer_value();
+        let i_p = builder.build_alloca(i32_type, "");
+        let j_p = builder.build_alloca(i64_type, "");
+        builder.build_store(i_p, i32_type.const_int(1, false));
+        builder.build_unconditional_branch(args);
+
+        // push every argument after argv[0] like `read` would, separated by spaces
+        builder.position_at_end(args);
+        let i = builder.build_load(i32_type, i_p, "").into_int_value();
+        builder.build_conditional_branch(
+            builder.build_int_compare(IntPredicate::SLT, i, argc, ""),
+            arg,
+            run,
+        );
+
+        builder.position_at_end(arg);
+        builder.build_store(j_p, i64_type.const_zero());
+        builder.build_conditional_branch(
+            builder.build_int_compare(IntPredicate::SGT, i, i32_type.const_int(1, false), ""),
+            separate,
+            chars,
+        );
+
+        builder.position_at_end(separate);
+        builder.build_call(push_byte_func, &[chr_type.const_int(' ' as u64, false).into()], "");
+        builder.build_unconditional_branch(chars);
+
+        builder.position_at_end(chars);
+        let arg_p = unsafe { builder.build_in_bounds_gep(str_type, argv, &[i.into()], "") };
+        let arg_v = builder.build_load(str_type, arg_p, "").into_pointer_value();
+        let j = builder.build_load(i64_type, j_p, "").into_int_value();
+        let c_p = unsafe { builder.build_in_bounds_gep(context.i8_type(), arg_v, &[j], "") };
+        let c = builder.build_load(context.i8_type(), c_p, "").into_int_value();
+        builder.build_conditional_branch(
+            builder.build_int_compare(IntPredicate::EQ, c, context.i8_type().const_zero(), ""),
+            next,
+            push,
+        );
+
+        builder.position_at_end(push);
+        builder.build_call(
+            push_byte_func,
+            &[builder.build_int_z_extend(c, chr_type, "").into()],
+            "",
+        );
+        builder.build_store(j_p, builder.build_int_add(j, i64_type.const_int(1, false), ""));
+        builder.build_unconditional_branch(chars);
+
+        builder.position_at_end(next);
+        builder.build_store(i_p, builder.build_int_add(i, i32_type.const_int(1, false), ""));
+        builder.build_unconditional_branch(args);
+
+        builder.position_at_end(run);
+        builder.build_call(entry_func.unwrap(), &[], "");
+        builder.build_return(Some(&i32_type.const_zero()));
+    } else if options.multicall.is_empty() {
+        let function = module.add_function("main", main_fn_type, None);
+        let basic_block = context.append_basic_block(function, "entry");
+        builder.position_at_end(basic_block);
+        builder.build_call(entry_func.unwrap(), &[], "");
+        builder.build_return(Some(&i32_type.const_zero()));
+    } else {
+        let argv_type = str_type.ptr_type(AddressSpace::default());
+        let main_type = i32_type.fn_type(&[i32_type.into(), argv_type.into()], false);
+        let function = module.add_function("main", main_type, None);
+
+        let sc_fn_type = i32_type.fn_type(&[str_type.into(), str_type.into()], false);
+        let sc_fn_val = module
+            .get_function("strcmp")
+            .unwrap_or(module.add_function("strcmp", sc_fn_type, None));
+
+        let sr_fn_type = str_type.fn_type(&[str_type.into(), i32_type.into()], false);
+        let sr_fn_val = module
+            .get_function("strrchr")
+            .unwrap_or(module.add_function("strrchr", sr_fn_type, None));
+
+        let basic_block = context.append_basic_block(function, "entry");
+        builder.position_at_end(basic_block);
+
+        let argc = function.get_nth_param(0).unwrap().into_int_value();
+        let argv = function.get_nth_param(1).unwrap().into_pointer_value();
+
+        // strip any directory from argv[0]
+        let arg0 = builder.build_load(str_type, argv, "").into_pointer_value();
+        let slash = builder
+            .build_call(
+                sr_fn_val,
+                &[arg0.into(), i32_type.const_int('/' as u64, false).into()],
+                "",
+            )
+            .try_as_basic_value()
+            .unwrap_left()
+            .into_pointer_value();
+        let after_slash = unsafe {
+            builder.build_in_bounds_gep(
+                context.i8_type(),
+                slash,
+                &[i64_type.const_int(1, false)],
+                "",
+            )
+        };
+        let invoked = builder.build_select(builder.build_is_null(slash, ""), arg0, after_slash, "");
+
+        // compare a name against every entry, calling and returning from the first match
+        let build_dispatch = |name: BasicValueEnum| {
+            for (entry_name, entry) in &options.multicall {
+                let expected = builder.build_global_string_ptr(entry_name, "");
+                let cmp = builder
+                    .build_call(sc_fn_val, &[name.into(), expected.as_pointer_value().into()], "")
+                    .try_as_basic_value()
+                    .unwrap_left()
+                    .into_int_value();
+
+                let matched = context.append_basic_block(function, "");
+                let next = context.append_basic_block(function, "");
+                builder.build_conditional_branch(
+                    builder.build_int_compare(IntPredicate::EQ, cmp, i32_type.const_zero(), ""),
+                    matched,
+                    next,
+                );
+
+                builder.position_at_end(matched);
+                builder.build_call(func_defs[entry], &[], "");
+                builder.build_return(Some(&i32_type.const_zero()));
+
+                builder.position_at_end(next);
+            }
+        };
+
+        build_dispatch(invoked);
+
+        // otherwise, try the first argument
+        let has_arg = context.append_basic_block(function, "");
+        let fail = context.append_basic_block(function, "");
+        builder.build_conditional_branch(
+            builder.build_int_compare(IntPredicate::SGT, argc, i32_type.const_int(1, false), ""),
+            has_arg,
+            fail,
+        );
+
+        builder.position_at_end(has_arg);
+        let arg1_p = unsafe {
+            builder.build_in_bounds_gep(str_type, argv, &[i64_type.const_int(1, false)], "")
+        };
+        let arg1 = builder.build_load(str_type, arg1_p, "");
+        build_dispatch(arg1);
+        builder.build_unconditional_branch(fail);
+
+        builder.position_at_end(fail);
+        let names: Vec<&str> = options.multicall.iter().map(|(n, _)| n.as_str()).collect();
+        let usage = format!("ERROR: expected one of: {}\n", names.join(", "));
+        let msg = builder.build_global_string_ptr(&usage, "");
+        builder.build_call(
+            wr_fn_val,
+            &[
+                i32_type.const_int(2, false).into(),
+                msg.as_pointer_value().into(),
+                i64_type.const_int(usage.len() as u64, false).into(),
+            ],
+            "",
+        );
+        builder.build_return(Some(&i32_type.const_int(1, false)));
+    }
+
+    Ok(Built {
+        module,
+        func_defs,
+        packages,
+    })
+}
+
+/// Bundles the objects into the archive at `archive`.
+fn archive(archive: &Path, objects: &[PathBuf]) -> Result<(), CompileError> {
+    // `ar` adds to an existing archive rather than replacing it
+    if archive.exists() {
+        fs::remove_file(archive)
+            .map_err(|e| CompileError::CannotWrite(archive.display().to_string(), e.to_string()))?;
+    }
+    let output = Command::new("ar")
+        .arg("rcs")
+        .arg(&archive)
+        .args(objects)
+        .output()
+        .map_err(|e| CompileError::ArchiveFailed(format!("cannot run `ar`: {}", e)))?;
+    if !output.status.success() {
+        return Err(CompileError::ArchiveFailed(
+            String::from_utf8_lossy(&output.stderr).trim().to_string(),
+        ));
+    }
+    Ok(())
+}
+
+fn optimization_level(level: u32) -> OptimizationLevel {
+    match level {
+        0 => OptimizationLevel::None,
+        1 => OptimizationLevel::Less,
+        2 => OptimizationLevel::Default,
+        _ => OptimizationLevel::Aggressive,
+    }
+}
+
+/// Optimises a copy of `module` at `level` and generates code for it, checking that the module
+/// verifies before and after.
+fn verify_at(
+    module: &Module,
+    target_machine: &TargetMachine,
+    level: u32,
+) -> Result<(), CompileError> {
+    let invalid = |e: String| CompileError::InvalidModule(level, e);
+    let module = module.clone();
+    module.verify().map_err(|e| invalid(e.to_string()))?;
+
+    let pass_manager_builder = PassManagerBuilder::create();
+    pass_manager_builder.set_optimization_level(optimization_level(level));
+    let pass_manager = PassManager::create(());
+    pass_manager_builder.populate_module_pass_manager(&pass_manager);
+    pass_manager.run_on(&module);
+    module.verify().map_err(|e| invalid(e.to_string()))?;
+
+    target_machine
+        .write_to_memory_buffer(&module, FileType::Object)
+        .map_err(|e| invalid(e.to_string()))?;
+    Ok(())
+}
+
+/// Builds the functions of one top-level package into a module of their own. Everything else they
+/// use, from the stack to functions in other packages, is declared and left to the linker.
+fn build_package_module<'a>(
+    context: &'a Context,
+    module_name: &str,
+    symbols: &HashMap<Vec<String>, String>,
+    funcs: Vec<(Vec<String>, Vec<AST>)>,
+    options: &CompileOptions,
+) -> Module<'a> {
+    let module = context.create_module(module_name);
+    let builder = context.create_builder();
+
+    let bool_type = context.bool_type();
+    let i64_type = context.i64_type();
+    let void_type = context.void_type();
+    let fn_type = void_type.fn_type(&[], false);
+    let str_type = context.i8_type().ptr_type(AddressSpace::default());
+
+    let stack = module.add_global(
+        bool_type.ptr_type(AddressSpace::default()),
+        Some(AddressSpace::default()),
+        "stack",
+    );
+    let index = module.add_global(i64_type, Some(AddressSpace::default()), "index");
+
+    let dec_func = module.add_function("decri", fn_type, None);
+    let inc_func = module.add_function("incri", fn_type, None);
+    let print_func = module.add_function("print", fn_type, None);
+    let read_func = module.add_function("read", fn_type, None);
+    let trap_func = module.add_function(
+        "trap",
+        void_type.fn_type(&[str_type.into(), i64_type.into()], false),
+        None,
+    );
+
+    let func_defs: HashMap<Vec<String>, FunctionValue> = symbols
+        .iter()
+        .map(|(name, symbol)| (name.clone(), module.add_function(symbol, fn_type, None)))
+        .collect();
+
+    for (name, asts) in funcs {
+        let function = func_defs[&name];
+        let basic_block = context.append_basic_block(function, "entry");
+        builder.position_at_end(basic_block);
+
+        let function_name = name.join(".");
+        let top = build_top(context, &builder, function);
+
+        build_ast(
+            asts,
+            &Env {
+                builder: &builder,
+                index: &index,
+                stack: &stack,
+                bool_type,
+                i64_type,
+                print_func,
+                function,
+                context,
+                dec_func,
+                inc_func,
+                func_defs: &func_defs,
+                read_func,
+                trap_func,
+                debug_stack: options.debug_stack,
+                stack_size: options.stack_size,
+                function_name: &function_name,
+                top: Some(top),
             },
+            true,
         );
 
         builder.build_return(None);
     }
 
-    let function = module.add_function("main", fn_type, None);
-    let basic_block = context.append_basic_block(function, "entry");
-    builder.position_at_end(basic_block);
-    builder.build_call(entry_func.unwrap(), &[], "");
+    module
+}
+
+/// The counters `instrument` keeps for each function, by its place in `slots`.
+struct Profile<'a> {
+    slots: HashMap<Vec<String>, u64>,
+    counters_type: ArrayType<'a>,
+    calls: GlobalValue<'a>,
+    cycles: GlobalValue<'a>,
+    i64_type: IntType<'a>,
+    read_cycles: FunctionValue<'a>,
+    /// Prints the counters, to be run at exit.
+    report: FunctionValue<'a>,
+}
+
+impl<'a> Profile<'a> {
+    fn counter(
+        &self,
+        builder: &Builder<'a>,
+        counters: GlobalValue<'a>,
+        name: &Vec<String>,
+    ) -> PointerValue<'a> {
+        let indices = [
+            self.i64_type.const_zero(),
+            self.i64_type.const_int(self.slots[name], false),
+        ];
+        unsafe {
+            builder.build_in_bounds_gep(
+                self.counters_type,
+                counters.as_pointer_value(),
+                &indices,
+                "",
+            )
+        }
+    }
+
+    /// Counts a call to `name`, returning the cycle counter to pass to `build_exit`.
+    fn build_entry(&self, builder: &Builder<'a>, name: &Vec<String>) -> IntValue<'a> {
+        let calls = self.counter(builder, self.calls, name);
+        let n = builder
+            .build_load(self.i64_type, calls, "")
+            .into_int_value();
+        builder.build_store(
+            calls,
+            builder.build_int_add(n, self.i64_type.const_int(1, false), ""),
+        );
+        self.build_read_cycles(builder)
+    }
+
+    /// Adds the cycles since `started` to the total for `name`.
+    fn build_exit(&self, builder: &Builder<'a>, name: &Vec<String>, started: IntValue<'a>) {
+        let elapsed = builder.build_int_sub(self.build_read_cycles(builder), started, "");
+        let cycles = self.counter(builder, self.cycles, name);
+        let total = builder
+            .build_load(self.i64_type, cycles, "")
+            .into_int_value();
+        builder.build_store(cycles, builder.build_int_add(total, elapsed, ""));
+    }
+
+    fn build_read_cycles(&self, builder: &Builder<'a>) -> IntValue<'a> {
+        builder
+            .build_call(self.read_cycles, &[], "")
+            .try_as_basic_value()
+            .left()
+            .unwrap()
+            .into_int_value()
+    }
+}
+
+/// Adds the counters for `instrument`, and a function that prints them.
+fn build_profile<'a>(
+    context: &'a Context,
+    module: &Module<'a>,
+    builder: &Builder<'a>,
+    func_defs: &HashMap<Vec<String>, FunctionValue<'a>>,
+) -> Profile<'a> {
+    let i32_type = context.i32_type();
+    let i64_type = context.i64_type();
+    let fn_type = context.void_type().fn_type(&[], false);
+    let str_type = context.i8_type().ptr_type(AddressSpace::default());
+
+    let mut names: Vec<_> = func_defs.keys().cloned().collect();
+    names.sort();
+    let counters_type = i64_type.array_type(names.len() as u32);
+    let add_counters = |name| {
+        let counters = module.add_global(counters_type, Some(AddressSpace::default()), name);
+        counters.set_initializer(&counters_type.const_zero());
+        counters
+    };
+    let dprintf = module.add_function(
+        "dprintf",
+        i32_type.fn_type(&[i32_type.into(), str_type.into()], true),
+        None,
+    );
+    let report = module.add_function("clink_profile_report", fn_type, None);
+    let profile = Profile {
+        slots: names.iter().cloned().zip(0..).collect(),
+        counters_type,
+        calls: add_counters("clink_calls"),
+        cycles: add_counters("clink_cycles"),
+        i64_type,
+        read_cycles: module.add_function(
+            "llvm.readcyclecounter",
+            i64_type.fn_type(&[], false),
+            None,
+        ),
+        report,
+    };
+
+    builder.position_at_end(context.append_basic_block(report, "entry"));
+    let format = builder.build_global_string_ptr("%s: %llu calls, %llu cycles\n", "");
+    for name in &names {
+        let label = builder.build_global_string_ptr(&name.join("."), "");
+        let calls = profile.counter(builder, profile.calls, name);
+        let cycles = profile.counter(builder, profile.cycles, name);
+        builder.build_call(
+            dprintf,
+            &[
+                i32_type.const_int(2, false).into(),
+                format.as_pointer_value().into(),
+                label.as_pointer_value().into(),
+                builder.build_load(i64_type, calls, "").into(),
+                builder.build_load(i64_type, cycles, "").into(),
+            ],
+            "",
+        );
+    }
     builder.build_return(None);
 
-    Target::initialize_all(&InitializationConfig::default());
-    // use the host machine as the compilation target
-    let target_triple = TargetMachine::get_default_triple();
-    let cpu = TargetMachine::get_host_cpu_name().to_string();
-    let features = TargetMachine::get_host_cpu_features().to_string();
+    profile
+}
 
-    // make a target from the triple
-    let target = Target::from_triple(&target_triple).unwrap();
+/// Adds a constructor that registers each of `handlers` with `atexit`. It runs before `main`, so
+/// they run however the program exits, short of aborting.
+fn build_at_exit<'a>(
+    context: &'a Context,
+    module: &Module<'a>,
+    builder: &Builder<'a>,
+    handlers: &[FunctionValue<'a>],
+) {
+    let i32_type = context.i32_type();
+    let fn_type = context.void_type().fn_type(&[], false);
+    let fn_ptr_type = fn_type.ptr_type(AddressSpace::default());
+    let str_type = context.i8_type().ptr_type(AddressSpace::default());
+
+    let atexit_type = i32_type.fn_type(&[fn_ptr_type.into()], false);
+    let atexit = module.add_function("atexit", atexit_type, None);
+    let init = module.add_function("clink_init", fn_type, None);
+    builder.position_at_end(context.append_basic_block(init, "entry"));
+    for handler in handlers {
+        builder.build_call(
+            atexit,
+            &[handler.as_global_value().as_pointer_value().into()],
+            "",
+        );
+    }
+    builder.build_return(None);
 
-    let target_machine = target
-        .create_target_machine(
-            &target_triple,
-            &cpu,
-            &features,
-            OptimizationLevel::Aggressive,
-            RelocMode::Default,
-            CodeModel::Default,
+    let ctor_type = context.struct_type(
+        &[i32_type.into(), fn_ptr_type.into(), str_type.into()],
+        false,
+    );
+    let ctor = ctor_type.const_named_struct(&[
+        i32_type.const_int(65535, false).into(),
+        init.as_global_value().as_pointer_value().into(),
+        str_type.const_null().into(),
+    ]);
+    let ctors = module.add_global(ctor_type.array_type(1), None, "llvm.global_ctors");
+    ctors.set_linkage(Linkage::Appending);
+    ctors.set_initializer(&ctor_type.const_array(&[ctor]));
+}
+
+/// Adds what `frame` needs, returning the function to print each byte through in place of
+/// `putchar`, and the one that closes the frame at exit.
+fn build_frame<'a>(
+    context: &'a Context,
+    module: &Module<'a>,
+    builder: &Builder<'a>,
+    frame: Frame,
+    putchar: FunctionValue<'a>,
+    abort: FunctionValue<'a>,
+) -> (FunctionValue<'a>, FunctionValue<'a>) {
+    let i8_type = context.i8_type();
+    let i32_type = context.i32_type();
+    let i64_type = context.i64_type();
+    let fn_type = context.void_type().fn_type(&[], false);
+    let str_type = i8_type.ptr_type(AddressSpace::default());
+
+    let close = module.add_function("clink_frame_close", fn_type, None);
+    let entry = context.append_basic_block(close, "entry");
+
+    let len_global = match frame {
+        Frame::Nul => {
+            builder.position_at_end(entry);
+            builder.build_call(putchar, &[i32_type.const_zero().into()], "");
+            builder.build_return(None);
+            return (putchar, close);
+        }
+        Frame::Len32 => module.add_global(i64_type, None, "clink_frame_len"),
+    };
+
+    // the length has to be known before any of the output is written, so keep it all until exit
+    len_global.set_initializer(&i64_type.const_zero());
+    let cap_global = module.add_global(i64_type, None, "clink_frame_cap");
+    cap_global.set_initializer(&i64_type.const_zero());
+    let buf_global = module.add_global(str_type, None, "clink_frame");
+    buf_global.set_initializer(&str_type.const_null());
+    let (len_p, cap_p, buf_p) = (
+        len_global.as_pointer_value(),
+        cap_global.as_pointer_value(),
+        buf_global.as_pointer_value(),
+    );
+
+    let realloc = module.get_function("realloc").unwrap_or_else(|| {
+        module.add_function(
+            "realloc",
+            str_type.fn_type(&[str_type.into(), i64_type.into()], false),
+            None,
         )
-        .unwrap();
+    });
+
+    let put = module.add_function(
+        "clink_frame_put",
+        context.void_type().fn_type(&[i32_type.into()], false),
+        None,
+    );
+    let put_entry = context.append_basic_block(put, "entry");
+    let grow = context.append_basic_block(put, "");
+    let failed = context.append_basic_block(put, "");
+    let grown = context.append_basic_block(put, "");
+    let store = context.append_basic_block(put, "");
+
+    builder.position_at_end(put_entry);
+    let len = builder.build_load(i64_type, len_p, "").into_int_value();
+    let cap = builder.build_load(i64_type, cap_p, "").into_int_value();
+    builder.build_conditional_branch(
+        builder.build_int_compare(IntPredicate::EQ, len, cap, ""),
+        grow,
+        store,
+    );
+
+    builder.position_at_end(grow);
+    let new_cap = builder.build_int_add(
+        builder.build_int_mul(cap, i64_type.const_int(2, false), ""),
+        i64_type.const_int(4096, false),
+        "",
+    );
+    let buf = builder.build_load(str_type, buf_p, "");
+    let new_buf = builder
+        .build_call(realloc, &[buf.into(), new_cap.into()], "")
+        .try_as_basic_value()
+        .unwrap_left()
+        .into_pointer_value();
+    builder.build_conditional_branch(builder.build_is_null(new_buf, ""), failed, grown);
+
+    builder.position_at_end(failed);
+    builder.build_call(abort, &[], "");
+    builder.build_unreachable();
+
+    builder.position_at_end(grown);
+    builder.build_store(buf_p, new_buf);
+    builder.build_store(cap_p, new_cap);
+    builder.build_unconditional_branch(store);
+
+    builder.position_at_end(store);
+    let buf = builder.build_load(str_type, buf_p, "").into_pointer_value();
+    let c = put.get_nth_param(0).unwrap().into_int_value();
+    unsafe {
+        let c_p = builder.build_in_bounds_gep(i8_type, buf, &[len], "");
+        builder.build_store(c_p, builder.build_int_truncate(c, i8_type, ""));
+    }
+    builder.build_store(
+        len_p,
+        builder.build_int_add(len, i64_type.const_int(1, false), ""),
+    );
+    builder.build_return(None);
 
-    let s = module_name.to_string() + ".o";
-    let output_filename = Path::new(&s);
-    target_machine
-        .write_to_file(&module, FileType::Object, output_filename)
-        .map_err(|e| format!("{:?}", e))
-        .unwrap();
+    let copy = context.append_basic_block(close, "");
+    let done = context.append_basic_block(close, "");
 
-    let mut cmd = Command::new("clang");
-    cmd.arg(output_filename)
-        .arg("-o")
-        .arg(Path::new(module_name)).output().expect(format!("ERROR: linking error (link manually with clang {} -o {}", output_filename.to_str().unwrap(), module_name).as_str());
-    
+    builder.position_at_end(entry);
+    let len = builder.build_load(i64_type, len_p, "").into_int_value();
+    for n in 0..4 {
+        let byte = builder.build_and(
+            builder.build_right_shift(len, i64_type.const_int(8 * n, false), false, ""),
+            i64_type.const_int(0xff, false),
+            "",
+        );
+        builder.build_call(
+            putchar,
+            &[builder.build_int_truncate(byte, i32_type, "").into()],
+            "",
+        );
+    }
+    let buf = builder.build_load(str_type, buf_p, "").into_pointer_value();
+    let i_p = builder.build_alloca(i64_type, "");
+    builder.build_store(i_p, i64_type.const_zero());
+    builder.build_conditional_branch(
+        builder.build_int_compare(IntPredicate::NE, len, i64_type.const_zero(), ""),
+        copy,
+        done,
+    );
+
+    builder.position_at_end(copy);
+    let i = builder.build_load(i64_type, i_p, "").into_int_value();
+    let c = unsafe {
+        let c_p = builder.build_in_bounds_gep(i8_type, buf, &[i], "");
+        builder.build_load(i8_type, c_p, "").into_int_value()
+    };
+    builder.build_call(
+        putchar,
+        &[builder.build_int_z_extend(c, i32_type, "").into()],
+        "",
+    );
+    let i = builder.build_int_add(i, i64_type.const_int(1, false), "");
+    builder.build_store(i_p, i);
+    builder.build_conditional_branch(
+        builder.build_int_compare(IntPredicate::ULT, i, len, ""),
+        copy,
+        done,
+    );
+
+    builder.position_at_end(done);
+    builder.build_return(None);
+
+    (put, close)
 }
 
 struct Env<'a> {
@@ -259,18 +1596,248 @@ struct Env<'a> {
     function: FunctionValue<'a>,
     context: &'a Context,
     func_defs: &'a HashMap<Vec<String>, FunctionValue<'a>>,
+    trap_func: FunctionValue<'a>,
+    debug_stack: bool,
+    /// The most bits the stack can hold, if it can't grow.
+    stack_size: Option<u32>,
+    function_name: &'a str,
+    /// Where a tail call back to this function jumps to, or `None` if the function does more after
+    /// its body, in which case tail calls are made like any other.
+    top: Option<BasicBlock<'a>>,
+}
+
+/// Starts a block after the entry block of `function`, which nothing can branch to, for its tail
+/// calls to itself to jump back to.
+fn build_top<'a>(
+    context: &'a Context,
+    builder: &Builder<'a>,
+    function: FunctionValue<'a>,
+) -> BasicBlock<'a> {
+    let top = context.append_basic_block(function, "top");
+    builder.build_unconditional_branch(top);
+    builder.position_at_end(top);
+    top
+}
+
+/// Branches to `trap_func` with `message` if `cond` holds, and continues building in a fresh block
+/// otherwise.
+fn build_guard<'a>(
+    context: &'a Context,
+    builder: &Builder<'a>,
+    function: FunctionValue<'a>,
+    trap_func: FunctionValue<'a>,
+    cond: IntValue<'a>,
+    message: &str,
+) {
+    let trap_block = context.append_basic_block(function, "");
+    let ok_block = context.append_basic_block(function, "");
+    builder.build_conditional_branch(cond, trap_block, ok_block);
+
+    builder.position_at_end(trap_block);
+    let text = format!("ERROR: {}\n", message);
+    let msg = builder.build_global_string_ptr(&text, "");
+    builder.build_call(
+        trap_func,
+        &[
+            msg.as_pointer_value().into(),
+            context.i64_type().const_int(text.len() as u64, false).into(),
+        ],
+        "",
+    );
+    builder.build_unreachable();
+
+    builder.position_at_end(ok_block);
+}
+
+/// Loads the address of the bottom of the stack, which moves whenever it grows.
+fn build_stack_base<'a>(
+    builder: &Builder<'a>,
+    stack: &GlobalValue<'a>,
+    bool_type: IntType<'a>,
+) -> PointerValue<'a> {
+    builder
+        .build_load(
+            bool_type.ptr_type(AddressSpace::default()),
+            stack.as_pointer_value(),
+            "",
+        )
+        .into_pointer_value()
+}
+
+/// Grows the stack if the index would become `i_nv`, past the end of it, to at least twice its
+/// size. It starts out in `initial`, which can't be given to `realloc`, so it's copied out of it
+/// the first time instead.
+fn build_grow<'a>(
+    context: &'a Context,
+    builder: &Builder<'a>,
+    function: FunctionValue<'a>,
+    trap_func: FunctionValue<'a>,
+    i_nv: IntValue<'a>,
+    (stack, capacity, initial): (GlobalValue<'a>, GlobalValue<'a>, GlobalValue<'a>),
+    (realloc, memcpy): (FunctionValue<'a>, FunctionValue<'a>),
+) {
+    let i64_type = context.i64_type();
+    let bool_type = context.bool_type();
+    let grow = context.append_basic_block(function, "");
+    let copy = context.append_basic_block(function, "");
+    let grown = context.append_basic_block(function, "");
+    let done = context.append_basic_block(function, "");
+
+    let cap_p = capacity.as_pointer_value();
+    let cap = builder.build_load(i64_type, cap_p, "").into_int_value();
+    builder.build_conditional_branch(
+        builder.build_int_compare(IntPredicate::UGE, i_nv, cap, ""),
+        grow,
+        done,
+    );
+
+    builder.position_at_end(grow);
+    let doubled = builder.build_int_mul(cap, i64_type.const_int(2, false), "");
+    let new_cap = builder
+        .build_select(
+            builder.build_int_compare(IntPredicate::UGT, doubled, i_nv, ""),
+            doubled,
+            builder.build_int_add(i_nv, i64_type.const_int(1, false), ""),
+            "",
+        )
+        .into_int_value();
+    let old = build_stack_base(builder, &stack, bool_type);
+    let in_initial = builder.build_int_compare(
+        IntPredicate::EQ,
+        builder.build_ptr_to_int(old, i64_type, ""),
+        builder.build_ptr_to_int(initial.as_pointer_value(), i64_type, ""),
+        "",
+    );
+    let from = builder.build_select(
+        in_initial,
+        bool_type.ptr_type(AddressSpace::default()).const_null(),
+        old,
+        "",
+    );
+    let new = builder
+        .build_call(realloc, &[from.into(), new_cap.into()], "")
+        .try_as_basic_value()
+        .unwrap_left()
+        .into_pointer_value();
+    build_guard(
+        context,
+        builder,
+        function,
+        trap_func,
+        builder.build_is_null(new, ""),
+        "out of memory: cannot grow the stack",
+    );
+    builder.build_conditional_branch(in_initial, copy, grown);
+
+    builder.position_at_end(copy);
+    builder.build_call(memcpy, &[new.into(), old.into(), cap.into()], "");
+    builder.build_unconditional_branch(grown);
+
+    builder.position_at_end(grown);
+    builder.build_store(stack.as_pointer_value(), new);
+    builder.build_store(cap_p, new_cap);
+    builder.build_unconditional_branch(done);
+
+    builder.position_at_end(done);
+}
+
+/// Traps with a stack overflow if the index would become `i_nv`, past the top of a stack holding
+/// `stack_size` bits. The interpreter's stack has no top, so a program that gets here can't carry
+/// on as it would there.
+fn build_overflow_check<'a>(
+    context: &'a Context,
+    builder: &Builder<'a>,
+    function: FunctionValue<'a>,
+    trap_func: FunctionValue<'a>,
+    i_nv: IntValue<'a>,
+    stack_size: u32,
+) {
+    let i64_type = context.i64_type();
+    let cond = builder.build_int_compare(
+        IntPredicate::UGT,
+        i_nv,
+        i64_type.const_int(stack_size.into(), false),
+        "",
+    );
+    build_guard(
+        context,
+        builder,
+        function,
+        trap_func,
+        cond,
+        &format!("stack overflow: the stack only holds {} bits", stack_size),
+    );
 }
 
-fn build_ast(asts: Vec<AST>, env: &Env) {
-    for ast in asts {
+/// Traps if pushing `count` bits would overflow the stack, when stack debugging is enabled and
+/// the stack can't grow.
+fn build_push_check(env: &Env, op: &str, count: u64) {
+    let stack_size = match env.stack_size {
+        Some(stack_size) if env.debug_stack => stack_size,
+        _ => return,
+    };
+    let i_v = env
+        .builder
+        .build_load(env.i64_type, env.index.as_pointer_value(), "")
+        .into_int_value();
+    let cond = env.builder.build_int_compare(
+        IntPredicate::UGT,
+        env.builder
+            .build_int_add(i_v, env.i64_type.const_int(count, false), ""),
+        env.i64_type.const_int(stack_size.into(), false),
+        "",
+    );
+    build_guard(
+        env.context,
+        env.builder,
+        env.function,
+        env.trap_func,
+        cond,
+        &format!("stack overflow in `{}` in function `{}`", op, env.function_name),
+    );
+}
+
+/// Traps if popping `count` bits would underflow the stack, when stack debugging is enabled.
+fn build_pop_check(env: &Env, op: &str, count: u64) {
+    if !env.debug_stack {
+        return;
+    }
+    let i_v = env
+        .builder
+        .build_load(env.i64_type, env.index.as_pointer_value(), "")
+        .into_int_value();
+    let cond = env.builder.build_int_compare(
+        IntPredicate::ULT,
+        i_v,
+        env.i64_type.const_int(count, false),
+        "",
+    );
+    build_guard(
+        env.context,
+        env.builder,
+        env.function,
+        env.trap_func,
+        cond,
+        &format!("stack underflow in `{}` in function `{}`", op, env.function_name),
+    );
+}
+
+/// Builds `asts` into the current block. If `tail` is set, nothing runs after them but the
+/// function's return, so a tail call at the end returns or loops itself.
+fn build_ast(asts: Vec<AST>, env: &Env, tail: bool) {
+    let len = asts.len();
+    for (i, ast) in asts.into_iter().enumerate() {
+        let tail = tail && i + 1 == len;
         match ast {
             AST::Left => {
+                build_push_check(env, "!", 1);
+
                 let i_p = env.index.as_pointer_value();
                 let i_v = env
                     .builder
                     .build_load(env.i64_type, i_p, "")
                     .into_int_value();
-                let s_p = env.stack.as_pointer_value();
+                let s_p = build_stack_base(env.builder, env.stack, env.bool_type);
 
                 unsafe {
                     let x_p = env
@@ -284,12 +1851,14 @@ fn build_ast(asts: Vec<AST>, env: &Env) {
                 env.builder.build_call(env.inc_func, &[], "");
             }
             AST::Right => {
+                build_push_check(env, "?", 1);
+
                 let i_p = env.index.as_pointer_value();
                 let i_v = env
                     .builder
                     .build_load(env.i64_type, i_p, "")
                     .into_int_value();
-                let s_p = env.stack.as_pointer_value();
+                let s_p = build_stack_base(env.builder, env.stack, env.bool_type);
 
                 unsafe {
                     let x_p = env
@@ -303,16 +1872,24 @@ fn build_ast(asts: Vec<AST>, env: &Env) {
                 env.builder.build_call(env.inc_func, &[], "");
             }
             AST::Print => {
+                build_pop_check(env, "#", 8);
                 env.builder.build_call(env.print_func, &[], "");
             }
             AST::Read => {
+                build_push_check(env, "@", 8);
                 env.builder.build_call(env.read_func, &[], "");
             }
             AST::Split(l, r) => {
-                let s_p = env.stack.as_pointer_value();
+                build_pop_check(env, ":", 1);
 
-                env.builder.build_call(env.dec_func, &[], "");
+                let s_p = build_stack_base(env.builder, env.stack, env.bool_type);
                 let i_p = env.index.as_pointer_value();
+                let i_ov = env
+                    .builder
+                    .build_load(env.i64_type, i_p, "")
+                    .into_int_value();
+
+                env.builder.build_call(env.dec_func, &[], "");
 
                 // dec i_p
                 let i_v = env
@@ -332,6 +1909,21 @@ fn build_ast(asts: Vec<AST>, env: &Env) {
                         .builder
                         .build_load(env.bool_type, x_p, "")
                         .into_int_value();
+                    // an empty stack pops `?`, not whatever was last left at the bottom
+                    let x_v = env
+                        .builder
+                        .build_select(
+                            env.builder.build_int_compare(
+                                IntPredicate::EQ,
+                                i_ov,
+                                env.i64_type.const_zero(),
+                                "",
+                            ),
+                            env.bool_type.const_zero(),
+                            x_v,
+                            "",
+                        )
+                        .into_int_value();
                     env.builder.build_conditional_branch(
                         env.builder.build_int_compare(
                             inkwell::IntPredicate::EQ,
@@ -346,20 +1938,34 @@ fn build_ast(asts: Vec<AST>, env: &Env) {
                     // if left
 
                     env.builder.position_at_end(left_block);
-                    build_ast(l, env);
+                    build_ast(l, env, tail);
                     env.builder.build_unconditional_branch(end_block);
 
                     // if right
 
                     env.builder.position_at_end(right_block);
-                    build_ast(r, env);
+                    build_ast(r, env, tail);
                     env.builder.build_unconditional_branch(end_block);
 
                     env.builder.position_at_end(end_block);
                 }
             }
-            AST::Bracketed(c) => build_ast(c, env),
-            AST::Id(id) => {
+            AST::Bracketed(c) => build_ast(c, env, tail),
+            AST::TailCall(id) if tail && env.top.is_some() => {
+                let callee = env.func_defs[&id];
+                if callee == env.function {
+                    // a loop, so it can't run out of call stack however many times it goes round
+                    env.builder.build_unconditional_branch(env.top.unwrap());
+                } else {
+                    let call = env.builder.build_call(callee, &[], "");
+                    call.set_tail_call(true);
+                    env.builder.build_return(None);
+                }
+                // the `br` or `ret` that comes after the body can't be reached from here
+                let rest = env.context.append_basic_block(env.function, "");
+                env.builder.position_at_end(rest);
+            }
+            AST::Id(id) | AST::TailCall(id) => {
                 env.builder.build_call(env.func_defs[&id], &[], "");
             }
         }
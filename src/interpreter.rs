@@ -1,97 +1,674 @@
-use std::{collections::HashMap, fmt::Display};
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap, VecDeque},
+    fmt::Display,
+    hash::{Hash, Hasher},
+    io::{self, stdin, stdout, BufWriter, Read, Write},
+};
+
+use serde_json::json;
 
 use crate::parser::{self, AST};
 
 #[derive(Debug)]
 pub enum RuntimeError {
     NoSuchFunction(Vec<String>),
+    OutputLimitExceeded(usize),
+    OutputError(String),
+    InputError(String),
+    /// An operation, given as its token, popped from an empty stack under `strict_stack`.
+    StackUnderflow(char),
+    TraceError(String),
+    /// A function kept being entered with the same stack under `detect_loops`.
+    LikelyInfiniteLoop(Vec<String>),
+    /// A call, to the function given, nested deeper than `max_depth`.
+    StackOverflow(Vec<String>),
 }
 
 impl Display for RuntimeError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             RuntimeError::NoSuchFunction(s) => write!(f, "ERROR: no such function {}", s.join(".")),
+            RuntimeError::OutputLimitExceeded(n) => {
+                write!(f, "ERROR: output limit of {} bytes exceeded", n)
+            }
+            RuntimeError::OutputError(e) => write!(f, "ERROR: cannot write output: {}", e),
+            RuntimeError::InputError(e) => write!(f, "ERROR: cannot read input: {}", e),
+            RuntimeError::StackUnderflow(op) => {
+                write!(f, "ERROR: `{}` popped from an empty stack", op)
+            }
+            RuntimeError::TraceError(e) => write!(f, "ERROR: cannot write trace: {}", e),
+            RuntimeError::LikelyInfiniteLoop(id) => {
+                writeln!(
+                    f,
+                    "ERROR: `{}` was entered {} times with the same stack and no input or output \
+                     in between, so it's probably looping forever",
+                    id.join("."),
+                    LOOP_REPEATS
+                )?;
+                write!(f, "HINT:  run without `--detect-loops` if it isn't")
+            }
+            RuntimeError::StackOverflow(id) => {
+                writeln!(
+                    f,
+                    "ERROR: calls nested too deep when calling `{}`, so it probably never stops \
+                     recursing",
+                    id.join(".")
+                )?;
+                write!(f, "HINT:  raise the limit with `--max-depth` if it does")
+            }
+        }
+    }
+}
+
+/// How deep calls can nest by default. Each call takes a few kilobytes of stack in a debug build,
+/// so this needs a few hundred megabytes, more than a thread gets unless asked for.
+pub const DEFAULT_MAX_DEPTH: usize = 100_000;
+
+pub struct InterpretOptions {
+    /// Maximum number of bytes the program may print, or 0 for no limit.
+    pub max_output: usize,
+    /// Print each byte as two hex digits, sixteen to a line, instead of as a character.
+    pub hex: bool,
+    /// Print bytes other than printable ASCII, newlines and tabs as `\xNN` escapes.
+    pub safe_output: bool,
+    /// The byte `@` reads once the input is exhausted.
+    pub eof_byte: u8,
+    /// Bytes pushed onto the stack before the entry runs, each the same way `@` pushes one.
+    pub args: Vec<u8>,
+    /// Store the stack 64 bits to a word rather than a byte per bit.
+    pub packed_stack: bool,
+    /// Fail when `:` or `#` pops from an empty stack, rather than popping `false`.
+    pub strict_stack: bool,
+    /// Fail when a function is entered with the same stack too many times without any input or
+    /// output happening, which usually means it will never stop.
+    pub detect_loops: bool,
+    /// The most calls that can be nested at once, or 0 for no limit, and `DEFAULT_MAX_DEPTH` by
+    /// default. Each call uses some of the interpreter's own stack, so with no limit, recursing
+    /// too deep crashes it.
+    pub max_depth: usize,
+    /// Wrap everything the program prints in a frame, closed once it stops.
+    pub frame: Option<Frame>,
+    /// How events are written when tracing.
+    pub trace_format: TraceFormat,
+}
+
+impl Default for InterpretOptions {
+    fn default() -> InterpretOptions {
+        InterpretOptions {
+            max_output: 0,
+            hex: false,
+            safe_output: false,
+            eof_byte: 0,
+            args: Vec::new(),
+            packed_stack: false,
+            strict_stack: false,
+            detect_loops: false,
+            max_depth: DEFAULT_MAX_DEPTH,
+            frame: None,
+            trace_format: TraceFormat::Json,
+        }
+    }
+}
+
+/// How trace events are written, one per line.
+#[derive(Clone, Copy, Default)]
+pub enum TraceFormat {
+    /// A JSON object, for tools to read.
+    #[default]
+    Json,
+    /// Plain text indented by call depth, for people to read.
+    Text,
+}
+
+/// How a program's output is framed for whatever reads it.
+#[derive(Clone, Copy)]
+pub enum Frame {
+    /// Follow the output with a NUL byte, like a C string.
+    Nul,
+    /// Put the output's length before it, as 4 little-endian bytes.
+    Len32,
+}
+
+/// How many of the most recent function entries `detect_loops` remembers.
+const LOOP_WINDOW: usize = 1024;
+
+/// How many times `detect_loops` lets the same function be entered with the same stack within the
+/// window. Some programs do this legitimately a few times, like calling a function that leaves the
+/// stack as it was twice in a row.
+const LOOP_REPEATS: usize = 16;
+
+/// What a program left behind once it finished.
+pub struct Finished {
+    /// The stack, from the bottom up.
+    pub stack: Vec<bool>,
+    /// The most bits the stack ever held.
+    pub peak_stack: usize,
+}
+
+/// Below this many elements, a stack keeps whatever it has allocated.
+const MIN_CAPACITY: usize = 4096;
+
+/// Gives back memory once a stack has shrunk to a quarter of its capacity, so a long-running
+/// program that once used a lot of stack doesn't keep holding on to it. Halving rather than
+/// shrinking to fit leaves room to grow again without reallocating straight away.
+fn shrink<T>(v: &mut Vec<T>) {
+    if v.capacity() > MIN_CAPACITY && v.len() < v.capacity() / 4 {
+        v.shrink_to(v.capacity() / 2);
+    }
+}
+
+/// The stack of bits, either a byte per bit or packed into words.
+#[derive(Hash)]
+enum Stack {
+    Unpacked(Vec<bool>),
+    Packed { words: Vec<u64>, len: usize },
+}
+
+impl Stack {
+    fn new(packed: bool) -> Stack {
+        if packed {
+            Stack::Packed {
+                words: Vec::new(),
+                len: 0,
+            }
+        } else {
+            Stack::Unpacked(Vec::new())
+        }
+    }
+
+    fn push(&mut self, bit: bool) {
+        match self {
+            Stack::Unpacked(bits) => bits.push(bit),
+            Stack::Packed { words, len } => {
+                if *len % 64 == 0 {
+                    words.push(0);
+                }
+                if bit {
+                    *words.last_mut().unwrap() |= 1 << (*len % 64);
+                }
+                *len += 1;
+            }
+        }
+    }
+
+    fn pop(&mut self) -> Option<bool> {
+        match self {
+            Stack::Unpacked(bits) => {
+                let bit = bits.pop();
+                shrink(bits);
+                bit
+            }
+            Stack::Packed { words, len } => {
+                if *len == 0 {
+                    return None;
+                }
+                *len -= 1;
+                let word = *words.last().unwrap();
+                // clear the bit so a later push can just set it
+                *words.last_mut().unwrap() &= !(1 << (*len % 64));
+                if *len % 64 == 0 {
+                    words.pop();
+                    shrink(words);
+                }
+                Some((word >> (*len % 64)) & 1 == 1)
+            }
+        }
+    }
+
+    fn len(&self) -> usize {
+        match self {
+            Stack::Unpacked(bits) => bits.len(),
+            Stack::Packed { len, .. } => *len,
+        }
+    }
+
+    /// The bit on top, or `None` if the stack is empty.
+    fn top(&self) -> Option<bool> {
+        match self {
+            Stack::Unpacked(bits) => bits.last().copied(),
+            Stack::Packed { len: 0, .. } => None,
+            Stack::Packed { words, len } => {
+                Some((words[(len - 1) / 64] >> ((len - 1) % 64)) & 1 == 1)
+            }
+        }
+    }
+
+    /// Every bit on the stack, from the bottom up.
+    fn into_bits(self) -> Vec<bool> {
+        match self {
+            Stack::Unpacked(bits) => bits,
+            Stack::Packed { words, len } => {
+                (0..len).map(|i| (words[i / 64] >> (i % 64)) & 1 == 1).collect()
+            }
         }
     }
+
+    fn reserve(&mut self, additional: usize) {
+        match self {
+            Stack::Unpacked(bits) => bits.reserve(additional),
+            Stack::Packed { words, len } => {
+                words.reserve((*len + additional).div_ceil(64) - words.len())
+            }
+        }
+    }
+}
+
+struct State<'a> {
+    program: &'a HashMap<Vec<String>, Vec<AST>>,
+    param: Stack,
+    input: &'a mut dyn Read,
+    output: &'a mut dyn Write,
+    options: &'a InterpretOptions,
+    printed: usize,
+    trace: Option<&'a mut dyn Write>,
+    /// How many calls deep the current node is.
+    depth: usize,
+    /// The most bits the stack has held so far.
+    peak_stack: usize,
+    /// Hashes of the function and stack at each of the latest function entries, oldest first,
+    /// since the last input or output.
+    entries: VecDeque<u64>,
+    /// How many times each hash appears in `entries`.
+    entry_counts: HashMap<u64, usize>,
 }
 
+/// Runs `entry` from `program`, reading from stdin and printing to stdout.
+///
+/// `program` maps fully-qualified function names to their bodies, and needs every function
+/// reachable from `entry` to be present. It doesn't have to come from `parse` - the interpreter
+/// never touches the filesystem, so a map built by hand or loaded from elsewhere works the same.
+///
+/// Calls nesting as deep as `max_depth` allows need far more stack than a thread gets by default,
+/// so run programs that recurse deeply on a thread given a bigger one.
 pub fn interpret(
     program: &HashMap<Vec<String>, Vec<AST>>,
     entry: Vec<String>,
-) -> Result<(), RuntimeError> {
-    let mut result = Vec::new();
-    do_ast(
+    options: &InterpretOptions,
+) -> Result<Finished, RuntimeError> {
+    let mut input = stdin().lock();
+    let mut output = BufWriter::new(stdout().lock());
+    interpret_with_io(program, entry, options, &mut input, &mut output, None)
+}
+
+/// Like `interpret`, but reading from `input` and printing to `output`. `input` is read a byte at a
+/// time, so wrap it in a `BufReader` if it's slow to read from directly.
+///
+/// If `trace` is given, an event is written to it for every node run, as a line of JSON like
+///
+/// ```text
+/// {"branch":"left","depth":1,"node":"split","stack":9,"top":true}
+/// ```
+///
+/// `node` is one of `left`, `right`, `print`, `read`, `split`, `bracket`, `call` or `tail_call`.
+/// `depth` is how many calls deep the node is, and `stack` is how many bits were on the stack
+/// just before it ran, with `top` the bit on top of them, or `null` if there were none. Splits
+/// add the `branch` taken, and calls add the `function` called.
+///
+/// With `TraceFormat::Text`, each event is the same information on a line indented by its depth,
+/// with bits written as `!` and `?`, like
+///
+/// ```text
+///   split left (stack 9, top !)
+/// ```
+pub fn interpret_with_io(
+    program: &HashMap<Vec<String>, Vec<AST>>,
+    entry: Vec<String>,
+    options: &InterpretOptions,
+    input: &mut dyn Read,
+    output: &mut dyn Write,
+    trace: Option<&mut dyn Write>,
+) -> Result<Finished, RuntimeError> {
+    // the length has to be known before any of the output is written
+    let mut framed = Vec::new();
+    let mut state = State {
         program,
-        &mut result,
+        param: Stack::new(options.packed_stack),
+        input,
+        output: match options.frame {
+            Some(Frame::Len32) => &mut framed,
+            _ => &mut *output,
+        },
+        options,
+        printed: 0,
+        trace: trace.map(|t| t as &mut dyn Write),
+        depth: 0,
+        peak_stack: 0,
+        entries: VecDeque::new(),
+        entry_counts: HashMap::new(),
+    };
+    for byte in &options.args {
+        push_byte(&mut state, *byte);
+    }
+    let result = do_ast(
+        &mut state,
         program
             .get(&entry)
             .ok_or(RuntimeError::NoSuchFunction(entry))?,
-    )?;
-    Ok(())
+    );
+    if options.hex && state.printed % 16 != 0 {
+        writeln!(state.output).map_err(output_error)?;
+    }
+    // flush whatever was printed before any error, too
+    state.output.flush().map_err(output_error)?;
+    if let Some(trace) = state.trace {
+        trace.flush().map_err(|e| RuntimeError::TraceError(e.to_string()))?;
+    }
+    let finished = Finished {
+        peak_stack: state.peak_stack,
+        stack: state.param.into_bits(),
+    };
+    // a compiled program aborts on an error, before it gets to frame its output
+    result?;
+    match options.frame {
+        Some(Frame::Nul) => output.write_all(&[0]),
+        Some(Frame::Len32) => {
+            let len = u32::try_from(framed.len())
+                .map_err(|_| RuntimeError::OutputLimitExceeded(u32::MAX as usize))?;
+            output
+                .write_all(&len.to_le_bytes())
+                .and_then(|_| output.write_all(&framed))
+        }
+        None => Ok(()),
+    }
+    .and_then(|_| output.flush())
+    .map_err(output_error)?;
+    Ok(finished)
 }
 
-fn do_ast(
-    program: &HashMap<Vec<String>, Vec<AST>>,
-    param: &mut Vec<bool>,
-    asts: &Vec<AST>,
-) -> Result<(), RuntimeError> {
-    for ast in asts {
+fn do_ast(state: &mut State, asts: &Vec<AST>) -> Result<(), RuntimeError> {
+    let mut i = 0;
+    while i < asts.len() {
+        let ast = &asts[i];
+        i += 1;
         match ast {
-            AST::Left => {
-                param.push(true);
-            }
-            AST::Right => {
-                param.push(false);
+            AST::Left | AST::Right => {
+                // push a whole run of literal bits at once
+                let start = i - 1;
+                while i < asts.len() && matches!(asts[i], AST::Left | AST::Right) {
+                    i += 1;
+                }
+                state.param.reserve(i - start);
+                for bit in &asts[start..i] {
+                    trace(state, bit, None)?;
+                    state.param.push(matches!(bit, AST::Left));
+                }
+                state.peak_stack = state.peak_stack.max(state.param.len());
             }
             parser::AST::Split(l, r) => {
-                if param.pop().unwrap_or(false) {
-                    do_ast(program, param, l)?;
+                // the trace shows the stack from before the pop
+                let before = state
+                    .trace
+                    .is_some()
+                    .then(|| (state.param.len(), state.param.top()));
+                let branch = pop(state, ':')?;
+                if let Some((stack, top)) = before {
+                    trace_event(state, ast, stack, top, Some(branch))?;
+                }
+                if branch {
+                    do_ast(state, l)?;
                 } else {
-                    do_ast(program, param, r)?;
+                    do_ast(state, r)?;
                 }
             }
             parser::AST::Bracketed(f) => {
-                do_ast(program, param, f)?;
+                trace(state, ast, None)?;
+                do_ast(state, f)?;
             }
-            parser::AST::Id(id) => {
-                let f = program.get(id).unwrap();
-                do_ast(program, param, f)?;
+            // the interpreter keeps no frames, so tail calls need nothing special
+            parser::AST::Id(id) | parser::AST::TailCall(id) => {
+                trace(state, ast, None)?;
+                if state.options.detect_loops {
+                    check_loop(state, id)?;
+                }
+                let f = state
+                    .program
+                    .get(id)
+                    .ok_or_else(|| RuntimeError::NoSuchFunction(id.clone()))?;
+                let max_depth = state.options.max_depth;
+                if max_depth != 0 && state.depth >= max_depth {
+                    return Err(RuntimeError::StackOverflow(id.clone()));
+                }
+                state.depth += 1;
+                do_ast(state, f)?;
+                state.depth -= 1;
             }
             parser::AST::Print => {
+                trace(state, ast, None)?;
+                forget_entries(state);
+                let max_output = state.options.max_output;
+                if max_output != 0 && state.printed >= max_output {
+                    return Err(RuntimeError::OutputLimitExceeded(max_output));
+                }
+                state.printed += 1;
+
+                // the first bit popped is the most significant, the reverse of `push_byte`
                 let mut total: u8 = 0;
                 for _ in 0..8 {
                     total *= 2;
-                    if param.pop().unwrap_or(false) {
+                    if pop(state, '#')? {
                         total += 1;
                     }
                 }
-                print!("{}", char::from(total));
-            }
-            parser::AST::Read => {
-                let mut code: u8 = read_char().try_into().unwrap();
-                for _ in 0..8 {
-                    if code % 2 == 0 {
-                        param.push(false);
+                if state.options.hex {
+                    if state.printed % 16 == 0 {
+                        writeln!(state.output, "{:02x}", total)
                     } else {
-                        param.push(true);
+                        write!(state.output, "{:02x} ", total)
                     }
-                    code /= 2;
+                } else if state.options.safe_output
+                    && !(total.is_ascii_graphic() || b" \n\t".contains(&total))
+                {
+                    write!(state.output, "\\x{:02x}", total)
+                } else {
+                    state.output.write_all(&[total])
+                }
+                .map_err(output_error)?;
+            }
+            parser::AST::Read => {
+                trace(state, ast, None)?;
+                forget_entries(state);
+                // make sure any prompt is visible before waiting for input
+                state.output.flush().map_err(output_error)?;
+                let code = match read_byte(state.input)? {
+                    Some(byte) => byte,
+                    None => state.options.eof_byte,
+                };
+                push_byte(state, code);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Records entering `id` with the current stack, failing if that has happened too often lately.
+fn check_loop(state: &mut State, id: &Vec<String>) -> Result<(), RuntimeError> {
+    let mut hasher = DefaultHasher::new();
+    id.hash(&mut hasher);
+    state.param.hash(&mut hasher);
+    let hash = hasher.finish();
+
+    state.entries.push_back(hash);
+    let count = state.entry_counts.entry(hash).or_insert(0);
+    *count += 1;
+    if *count >= LOOP_REPEATS {
+        return Err(RuntimeError::LikelyInfiniteLoop(id.clone()));
+    }
+    if state.entries.len() > LOOP_WINDOW {
+        let oldest = state.entries.pop_front().unwrap();
+        *state.entry_counts.get_mut(&oldest).unwrap() -= 1;
+    }
+    Ok(())
+}
+
+/// Input and output count as progress, so entries before them can't be part of a loop.
+fn forget_entries(state: &mut State) {
+    state.entries.clear();
+    state.entry_counts.clear();
+}
+
+/// Writes the trace event for `ast` if tracing, with the stack as it is now.
+fn trace(state: &mut State, ast: &AST, branch: Option<bool>) -> Result<(), RuntimeError> {
+    if state.trace.is_none() {
+        return Ok(());
+    }
+    let stack = state.param.len();
+    let top = state.param.top();
+    trace_event(state, ast, stack, top, branch)
+}
+
+fn trace_event(
+    state: &mut State,
+    ast: &AST,
+    stack: usize,
+    top: Option<bool>,
+    branch: Option<bool>,
+) -> Result<(), RuntimeError> {
+    let node = match ast {
+        AST::Left => "left",
+        AST::Right => "right",
+        AST::Print => "print",
+        AST::Read => "read",
+        AST::Split(..) => "split",
+        AST::Bracketed(..) => "bracket",
+        AST::Id(..) => "call",
+        AST::TailCall(..) => "tail_call",
+    };
+    let branch = branch.map(|branch| if branch { "left" } else { "right" });
+    let function = match ast {
+        AST::Id(id) | AST::TailCall(id) => Some(id.join(".")),
+        _ => None,
+    };
+    let line = match state.options.trace_format {
+        TraceFormat::Json => {
+            let mut event =
+                json!({ "node": node, "depth": state.depth, "stack": stack, "top": top });
+            if let Some(branch) = branch {
+                event["branch"] = json!(branch);
+            }
+            if let Some(function) = function {
+                event["function"] = json!(function);
+            }
+            event.to_string()
+        }
+        TraceFormat::Text => {
+            let mut line = format!("{:indent$}{}", "", node, indent = state.depth * 2);
+            for detail in branch.into_iter().chain(function.as_deref()) {
+                line += " ";
+                line += detail;
+            }
+            match top {
+                Some(top) => {
+                    line += &format!(" (stack {}, top {})", stack, if top { '!' } else { '?' })
                 }
+                None => line += " (stack 0)",
             }
+            line
         }
+    };
+    if let Some(trace) = &mut state.trace {
+        writeln!(trace, "{}", line).map_err(|e| RuntimeError::TraceError(e.to_string()))?;
     }
     Ok(())
 }
 
-fn read_char() -> char {
-    use std::io::stdin;
-    let mut s = String::new();
-    stdin().read_line(&mut s).unwrap();
-    if let Some('\n') = s.chars().next_back() {
-        s.pop();
+/// Pops a bit for `op`, which is `false` if the stack is empty unless `strict_stack` is set.
+fn pop(state: &mut State, op: char) -> Result<bool, RuntimeError> {
+    match state.param.pop() {
+        Some(bit) => Ok(bit),
+        None if state.options.strict_stack => Err(RuntimeError::StackUnderflow(op)),
+        None => Ok(false),
+    }
+}
+
+/// Pushes the bits of `code`, least significant first.
+fn push_byte(state: &mut State, mut code: u8) {
+    for _ in 0..8 {
+        if code % 2 == 0 {
+            state.param.push(false);
+        } else {
+            state.param.push(true);
+        }
+        code /= 2;
     }
-    if let Some('\r') = s.chars().next_back() {
-        s.pop();
+    state.peak_stack = state.peak_stack.max(state.param.len());
+}
+
+fn output_error(e: std::io::Error) -> RuntimeError {
+    RuntimeError::OutputError(e.to_string())
+}
+
+/// Reads the next byte, exactly as it is, or `None` at the end of the input, the same as
+/// `getchar` in a compiled program.
+fn read_byte(input: &mut dyn Read) -> Result<Option<u8>, RuntimeError> {
+    let mut byte = [0];
+    match input.read_exact(&mut byte) {
+        Ok(()) => Ok(Some(byte[0])),
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(None),
+        Err(e) => Err(RuntimeError::InputError(e.to_string())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Runs `entry` in `program` with no input, returning what it printed.
+    fn run(program: &HashMap<Vec<String>, Vec<AST>>, entry: &str) -> Result<Vec<u8>, RuntimeError> {
+        let entry = entry.split('.').map(|x| x.to_string()).collect();
+        let mut output = Vec::new();
+        let options = InterpretOptions::default();
+        interpret_with_io(
+            program,
+            entry,
+            &options,
+            &mut io::empty(),
+            &mut output,
+            None,
+        )?;
+        Ok(output)
+    }
+
+    #[test]
+    fn missing_function_is_an_error() {
+        let program = HashMap::from([(
+            vec!["main".to_string(), "_".to_string()],
+            vec![
+                AST::Print,
+                AST::Id(vec!["main".to_string(), "gone".to_string()]),
+            ],
+        )]);
+        match run(&program, "main._") {
+            Err(RuntimeError::NoSuchFunction(id)) => assert_eq!(id, ["main", "gone"]),
+            Err(e) => panic!("expected no such function, found {}", e),
+            Ok(_) => panic!("expected no such function, but it ran"),
+        }
+    }
+
+    #[test]
+    fn error_leaves_output_unframed() {
+        let mut body = vec![AST::Left; 8];
+        body.extend([AST::Print, AST::Id(vec!["gone".to_string()])]);
+        let program = HashMap::from([(vec!["_".to_string()], body)]);
+        let options = InterpretOptions {
+            frame: Some(Frame::Nul),
+            ..Default::default()
+        };
+        let mut output = Vec::new();
+        let result = interpret_with_io(
+            &program,
+            vec!["_".to_string()],
+            &options,
+            &mut io::empty(),
+            &mut output,
+            None,
+        );
+        assert!(matches!(result, Err(RuntimeError::NoSuchFunction(_))));
+        assert_eq!(output, [0xff]);
+    }
+
+    #[test]
+    fn empty_entry_prints_nothing() {
+        let program = HashMap::from([(vec!["_".to_string()], Vec::new())]);
+        assert_eq!(run(&program, "_").unwrap(), b"");
     }
-    s.chars().next().unwrap()
 }
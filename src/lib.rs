@@ -0,0 +1,36 @@
+//! Parsing, interpreting and compiling clink programs.
+//!
+//! `parse` a project, then `interpret` or `compile` the program it returns, or use
+//! `interpret_with_io` to give it input and collect its output somewhere other than stdin and
+//! stdout.
+
+mod analysis;
+mod compiler;
+mod interpreter;
+mod parser;
+mod specialize;
+
+pub use compiler::{compile, CompileError, CompileOptions};
+pub use interpreter::{
+    interpret, interpret_with_io, Finished, Frame, InterpretOptions, RuntimeError, TraceFormat,
+};
+pub use parser::{
+    parse, FileFilter, ParseError, ParseReport, ParseWarning, Resolution, SourceFile, Span, Token,
+    AST,
+};
+
+/// Everything else the `clink` binary is built from. It isn't part of the public API, and can
+/// change in any release.
+#[doc(hidden)]
+pub mod cli {
+    pub use crate::analysis::{
+        dead_code, longest_call_chain, max_stack_depth, output_alphabet, OutputAlphabet, StackDepth,
+    };
+    pub use crate::compiler::{run_bitcode, run_jit};
+    pub use crate::interpreter::DEFAULT_MAX_DEPTH;
+    pub use crate::parser::{
+        canonicalize, check_all, is_archive, parse_body, parse_entries, parse_project,
+        program_from_json, program_to_json, scan, Project,
+    };
+    pub use crate::specialize::specialize;
+}
@@ -1,110 +1,1604 @@
-use std::{env::{self, current_dir}, path::{Path, Component}};
+use std::{
+    collections::{BTreeSet, HashMap},
+    env::{self, current_dir},
+    fs,
+    io::{self, Read, Write},
+    path::{Component, Path, PathBuf},
+    process, thread,
+};
 
-use compiler::compile;
-use interpreter::interpret;
-use parser::parse;
+use clink::cli::{
+    canonicalize, check_all, dead_code, is_archive, longest_call_chain, max_stack_depth,
+    output_alphabet, parse_body, parse_entries, parse_project, program_from_json, program_to_json,
+    run_bitcode, run_jit, scan, specialize, OutputAlphabet, Project, StackDepth, DEFAULT_MAX_DEPTH,
+};
+use clink::{
+    compile, interpret, interpret_with_io, parse, CompileError, CompileOptions, FileFilter,
+    Finished, Frame, InterpretOptions, ParseError, ParseReport, ParseWarning, RuntimeError,
+    SourceFile, TraceFormat, AST,
+};
 
-mod compiler;
-mod interpreter;
-mod parser;
+/// The stack commands run with. The interpreter recurses for every call, so it needs far more
+/// than the main thread has to reach `DEFAULT_MAX_DEPTH`.
+const COMMAND_STACK_SIZE: usize = 1 << 30;
 
 fn main() {
     env::set_var("RUST_BACKTRACE", "1");
     let args: Vec<String> = env::args().collect();
 
+    let handle = thread::Builder::new()
+        .stack_size(COMMAND_STACK_SIZE)
+        .spawn(move || dispatch(&args))
+        .expect("cannot start command thread");
+    if handle.join().is_err() {
+        // the panic has already been reported
+        process::exit(101);
+    }
+}
+
+/// Runs the command named by the first argument.
+fn dispatch(args: &[String]) {
     match args.get(1) {
         Some(s) => match s.as_str() {
-            "run" => {
-                let a = args.get(2);
-                match a {
-                    Some(a) => run(a),
-                    None => {
-                        println!("ERROR: expected file");
-                    }
-                }
-            }
+            "run" => run(&options_or_exit(&args[2..], 1)),
+            "check" => check(&options_or_exit(&args[2..], 1)),
+            "tree" => tree(&options_or_exit(&args[2..], 1)),
+            "ast" => print_program(&options_or_exit(&args[2..], 1)),
+            "specialize" => specialize_program(&options_or_exit(&args[2..], 1)),
+            "diff" => diff(&options_or_exit(&args[2..], 2)),
+            "test" => test(&options_or_exit(&args[2..], 0)),
+            // for checking what a snippet does by hand, so it isn't listed in `help`
+            "eval-bits" => eval_bits(&options_or_exit(&args[2..], 1)),
             "help" => {
                 println!("Available commands:\n");
-                println!("help          this command");
-                println!("run <file>    interpret clink file");
-            }
-            "build" => {
-                let a = args.get(2);
-                match a {
-                    Some(a) => do_compile(a),
-                    None => {
-                        println!("ERROR: expected file");
-                    }
-                }
+                println!("help                  this command");
+                println!("run <file> [-- args]  interpret clink file, with any args pushed onto the stack");
+                println!("build <file>          compile clink file");
+                println!("check <file>          parse clink file without running it, exiting with 1 if it's broken");
+                println!("tree                  show the package tree and the functions in each file");
+                println!("ast <file>            show each function the parser produced, with nodes in the order they run");
+                println!("specialize <file>     print the program as JSON with its first input fixed by --input");
+                println!("diff <a> <b>          run two clink files on the same --input file and compare their output");
+                println!("test                  run every %test in the project and report any that fail");
+                println!("\nOptions:\n");
+                println!("--root <dir>          add a source root (repeatable, defaults to the current directory)");
+                println!("--only <glob>         only read files under a root matching the glob, like **/*.clink (repeatable)");
+                println!("--exclude <glob>      skip files under a root matching the glob (repeatable)");
+                println!("--debug-stack         check every stack access in the compiled program");
+                println!("--max-output <n>      stop the interpreter after printing n bytes (0 for no limit)");
+                println!("--max-depth <n>       stop the interpreter when calls nest deeper than n (default 100000, 0 for no limit)");
+                println!("--eof-byte <n>        the byte read at the end of the input (default 0)");
+                println!("--hex                 print the interpreted program's output as hex bytes");
+                println!("--check-determinism   run the program twice on the same input and fail if the output differs");
+                println!("--trace               log every node run to stderr, indented by call depth");
+                println!("--trace-format <fmt>  log every node run as `text` like --trace, or `json`, one object per line");
+                println!("--trace-file <file>   write the trace to <file> instead of stderr");
+                println!("--detect-loops        stop the interpreter if a function keeps being entered with the same stack");
+                println!("--peak-stack          print the most bits the interpreter's stack held to stderr after running");
+                println!("--strict-stack        make popping from an empty stack an error in the interpreter");
+                println!("--packed-stack        pack the interpreter's stack into words instead of a byte per bit");
+                println!("--safe-output         escape unprintable bytes in the interpreted program's output");
+                println!("--frame nul|len32     end the output with a NUL byte, or start it with its length as 4 little-endian bytes");
+                println!("--deny-warnings       treat warnings as errors");
+                println!("--quiet               don't print warnings, only errors and the program's output");
+                println!("--strict-case         treat names that differ only by case as errors");
+                println!("--entry <name>        run the given fully-qualified function instead of `_`");
+                println!("--from-ast <file>     run a program loaded from a JSON AST instead of parsing");
+                println!("--compiled            run the program compiled in memory instead of interpreting it");
+                println!("--bc <file>           run a program built with --emit-bc, without parsing or building it again");
+                println!("--canonicalize        flatten brackets in the program before running, building or specializing it");
+                println!("--input <bits|file>   the input to specialize for as bytes written in binary, or to diff with");
+                println!("--stack-depth         estimate the maximum stack depth when checking");
+                println!("--max-chain           report the longest call chain when checking");
+                println!("--output-alphabet     list every byte the program could print when checking");
+                println!("--print-resolved      list what every function reference resolved to on stderr");
+                println!("--verbose             list every name tried when a function reference can't be resolved");
+                println!("--packages-only       only scan packages when checking, without resolving functions");
+                println!("--all                 resolve every function when checking, not just those the entry reaches");
+                println!("--lto                 build with link-time optimisation");
+                println!("--instrument          make the built program print each function's calls and cycles to stderr on exit");
+                println!("--lib                 build a static library lib<name>.a with no main, instead of a binary");
+                println!("--embed-abi           build a library exporting the entry as a byte-to-byte function, clink_entry");
+                println!("--verify-at <levels>  fail the build unless it verifies at each of these levels, e.g. O0,O3");
+                println!("--opt <0-3>           the optimisation level to build at (default 3)");
+                println!("--cc <path>           link with the given compiler instead of clang (or set CLINK_CC)");
+                println!("--push-args           push the built program's arguments onto the stack when it starts");
+                println!("--split-modules       build each top-level package into its own object before linking");
+                println!("--embed-hash          embed a hash of the sources in the built binary as `clink_source_hash`");
+                println!("--emit-header <file>  write a C header declaring the built functions");
+                println!("--dump-symbols        list the symbol each function is compiled to when building");
+                println!("--emit-ir             also write the LLVM IR of the build to <name>.ll");
+                println!("--emit-asm            also write the native assembly of the build to <name>.s");
+                println!("--emit-bc             also write the build as bitcode to <name>.bc, for `clink run --bc`");
+                println!("--target <triple>     build for another machine, like aarch64-unknown-linux-gnu");
+                println!("-o <path>             write the binary, or the library, here instead of the project folder");
+                println!("--deps <file>         write a Makefile rule listing the sources the build read");
+                println!("--no-bounds-check     UNSAFE: don't keep the compiled stack index in bounds");
+                println!("--stack-size <n>      the most bits the compiled stack can hold (default no limit)");
+                println!("--multicall <a,b,..>  build one binary that runs a.clink, b.clink, .. depending on its name");
             }
+            "build" => do_compile(&options_or_exit(&args[2..], 1)),
             _ => {
-                println!("ERROR: unknown command");
-                println!("HINT:  type 'clink help' for commands");
+                eprintln!("ERROR: unknown command");
+                eprintln!("HINT:  type 'clink help' for commands");
+                process::exit(1);
             }
         }
         _ => {
-            println!("ERROR: expected command");
-            println!("HINT:  type 'clink help' for commands");
+            eprintln!("ERROR: expected command");
+            eprintln!("HINT:  type 'clink help' for commands");
+            process::exit(1);
         }
     }
 }
 
-fn run(file: &String) {
+struct Options {
+    file: Option<String>,
+    /// Every file given, for commands that take more than one.
+    files: Vec<String>,
+    roots: Vec<PathBuf>,
+    debug_stack: bool,
+    max_output: usize,
+    multicall: Vec<String>,
+    deny_warnings: bool,
+    quiet: bool,
+    strict_case: bool,
+    hex: bool,
+    stack_depth: bool,
+    entry: Option<String>,
+    from_ast: Option<String>,
+    lto: bool,
+    safe_output: bool,
+    eof_byte: u8,
+    max_chain: bool,
+    output_alphabet: bool,
+    packages_only: bool,
+    no_bounds_check: bool,
+    print_resolved: bool,
+    cc: Option<String>,
+    dump_symbols: bool,
+    deps: Option<String>,
+    header: Option<String>,
+    split_modules: bool,
+    embed_hash: bool,
+    input: Option<String>,
+    push_args: bool,
+    args: Vec<String>,
+    packed_stack: bool,
+    strict_stack: bool,
+    check_determinism: bool,
+    trace_format: Option<TraceFormat>,
+    /// Whichever of `--trace` and `--trace-format` asked for the trace, to name in errors.
+    trace_flag: &'static str,
+    trace_file: Option<String>,
+    verify_at: Vec<u32>,
+    filter: FileFilter,
+    detect_loops: bool,
+    lib: bool,
+    peak_stack: bool,
+    instrument: bool,
+    frame: Option<Frame>,
+    embed_abi: bool,
+    canonicalize: bool,
+    verbose: bool,
+    compiled: bool,
+    emit_ir: bool,
+    emit_asm: bool,
+    all: bool,
+    opt_level: Option<u32>,
+    emit_bc: bool,
+    bc: Option<String>,
+    target: Option<String>,
+    output: Option<String>,
+    stack_size: Option<u32>,
+    max_depth: Option<usize>,
+}
 
-    let path = Path::new(file).to_path_buf();
+/// Like `parse_options`, but prints the error and exits if the options are bad.
+fn options_or_exit(args: &[String], max_files: usize) -> Options {
+    match parse_options(args, max_files) {
+        Ok(options) => options,
+        Err(e) => {
+            eprintln!("{}", e);
+            process::exit(1);
+        }
+    }
+}
+
+fn parse_options(args: &[String], max_files: usize) -> Result<Options, String> {
+    let mut files = Vec::new();
+    let mut roots = Vec::new();
+    let mut debug_stack = false;
+    let mut max_output = 0;
+    let mut multicall = Vec::new();
+    let mut deny_warnings = false;
+    let mut quiet = false;
+    let mut strict_case = false;
+    let mut hex = false;
+    let mut stack_depth = false;
+    let mut entry = None;
+    let mut from_ast = None;
+    let mut lto = false;
+    let mut safe_output = false;
+    let mut eof_byte = 0;
+    let mut max_chain = false;
+    let mut output_alphabet = false;
+    let mut packages_only = false;
+    let mut no_bounds_check = false;
+    let mut print_resolved = false;
+    let mut dump_symbols = false;
+    let mut deps = None;
+    let mut header = None;
+    let mut split_modules = false;
+    let mut embed_hash = false;
+    let mut input = None;
+    let mut push_args = false;
+    let mut packed_stack = false;
+    let mut strict_stack = false;
+    let mut check_determinism = false;
+    let mut trace_format = None;
+    let mut trace_flag = "--trace";
+    let mut trace_file = None;
+    let mut verify_at = Vec::new();
+    let mut filter = FileFilter::default();
+    let mut detect_loops = false;
+    let mut lib = false;
+    let mut peak_stack = false;
+    let mut instrument = false;
+    let mut frame = None;
+    let mut embed_abi = false;
+    let mut canonicalize = false;
+    let mut verbose = false;
+    let mut compiled = false;
+    let mut emit_ir = false;
+    let mut emit_asm = false;
+    let mut all = false;
+    let mut opt_level = None;
+    let mut emit_bc = false;
+    let mut bc = None;
+    let mut target = None;
+    let mut output = None;
+    let mut stack_size = None;
+    let mut max_depth = None;
+    let mut program_args = Vec::new();
+    let mut cc = env::var("CLINK_CC").ok();
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--root" => match args.next() {
+                Some(root) => roots.push(PathBuf::from(root)),
+                None => return Err("ERROR: expected directory after `--root`".to_string()),
+            },
+            "--debug-stack" => debug_stack = true,
+            "--deny-warnings" => deny_warnings = true,
+            "--quiet" => quiet = true,
+            "--strict-case" => strict_case = true,
+            "--hex" => hex = true,
+            "--lto" => lto = true,
+            "--lib" => lib = true,
+            "--embed-abi" => embed_abi = true,
+            "--canonicalize" => canonicalize = true,
+            "--verbose" => verbose = true,
+            "--compiled" => compiled = true,
+            "--emit-ir" => emit_ir = true,
+            "--emit-asm" => emit_asm = true,
+            "--emit-bc" => emit_bc = true,
+            "--bc" => match args.next() {
+                Some(file) => bc = Some(file.clone()),
+                None => return Err("ERROR: expected file after `--bc`".to_string()),
+            },
+            "--all" => all = true,
+            "--instrument" => instrument = true,
+            "--safe-output" => safe_output = true,
+            "--eof-byte" => {
+                eof_byte = parse_number(arg, args.next())?
+                    .try_into()
+                    .map_err(|_| "ERROR: `--eof-byte` must be at most 255".to_string())?
+            }
+            "--stack-depth" => stack_depth = true,
+            "--max-chain" => max_chain = true,
+            "--output-alphabet" => output_alphabet = true,
+            "--packages-only" => packages_only = true,
+            "--no-bounds-check" => no_bounds_check = true,
+            "--stack-size" => match u32::try_from(parse_number(arg, args.next())?) {
+                // the compiled stack needs one bit more than it holds
+                Ok(size) if size > 0 && size < u32::MAX => stack_size = Some(size),
+                _ => {
+                    return Err(format!(
+                        "ERROR: expected a stack size from 1 to {} bits",
+                        u32::MAX - 1
+                    ))
+                }
+            },
+            "--print-resolved" => print_resolved = true,
+            "--dump-symbols" => dump_symbols = true,
+            "--push-args" => push_args = true,
+            "--split-modules" => split_modules = true,
+            "--embed-hash" => embed_hash = true,
+            "--packed-stack" => packed_stack = true,
+            "--strict-stack" => strict_stack = true,
+            "--check-determinism" => check_determinism = true,
+            "--detect-loops" => detect_loops = true,
+            "--peak-stack" => peak_stack = true,
+            "--" => {
+                program_args = args.cloned().collect();
+                break;
+            }
+            "--entry" => match args.next() {
+                Some(name) => entry = Some(name.clone()),
+                None => return Err("ERROR: expected function after `--entry`".to_string()),
+            },
+            "--input" => match args.next() {
+                Some(value) => input = Some(value.clone()),
+                None => return Err("ERROR: expected input after `--input`".to_string()),
+            },
+            "--emit-header" => match args.next() {
+                Some(file) => header = Some(file.clone()),
+                None => return Err("ERROR: expected file after `--emit-header`".to_string()),
+            },
+            "--trace" => {
+                trace_format = Some(TraceFormat::Text);
+                trace_flag = "--trace";
+            }
+            "--trace-format" => {
+                match args.next().map(|f| f.as_str()) {
+                    Some("text") => trace_format = Some(TraceFormat::Text),
+                    Some("json") => trace_format = Some(TraceFormat::Json),
+                    Some(format) => {
+                        return Err(format!("ERROR: unknown trace format `{}`", format));
+                    }
+                    None => return Err("ERROR: expected format after `--trace-format`".to_string()),
+                }
+                trace_flag = "--trace-format";
+            }
+            "--frame" => match args.next().map(|f| f.as_str()) {
+                Some("nul") => frame = Some(Frame::Nul),
+                Some("len32") => frame = Some(Frame::Len32),
+                Some(f) => {
+                    return Err(format!("ERROR: unknown frame `{}`", f));
+                }
+                None => return Err("ERROR: expected `nul` or `len32` after `--frame`".to_string()),
+            },
+            "--trace-file" => match args.next() {
+                Some(file) => trace_file = Some(file.clone()),
+                None => return Err("ERROR: expected file after `--trace-file`".to_string()),
+            },
+            "--only" => match args.next() {
+                Some(glob) => filter.only.push(glob.clone()),
+                None => return Err("ERROR: expected glob after `--only`".to_string()),
+            },
+            "--exclude" => match args.next() {
+                Some(glob) => filter.exclude.push(glob.clone()),
+                None => return Err("ERROR: expected glob after `--exclude`".to_string()),
+            },
+            "--verify-at" => match args.next() {
+                Some(levels) => {
+                    for level in levels.split(",") {
+                        verify_at.push(parse_opt_level(level)?);
+                    }
+                }
+                None => return Err("ERROR: expected levels after `--verify-at`".to_string()),
+            },
+            "--deps" => match args.next() {
+                Some(file) => deps = Some(file.clone()),
+                None => return Err("ERROR: expected file after `--deps`".to_string()),
+            },
+            "-o" => match args.next() {
+                Some(path) => output = Some(path.clone()),
+                None => return Err("ERROR: expected path after `-o`".to_string()),
+            },
+            "--target" => match args.next() {
+                Some(triple) => target = Some(triple.clone()),
+                None => return Err("ERROR: expected target triple after `--target`".to_string()),
+            },
+            "--cc" => match args.next() {
+                Some(path) => cc = Some(path.clone()),
+                None => return Err("ERROR: expected compiler after `--cc`".to_string()),
+            },
+            "--from-ast" => match args.next() {
+                Some(file) => from_ast = Some(file.clone()),
+                None => return Err("ERROR: expected file after `--from-ast`".to_string()),
+            },
+            "--max-output" => max_output = parse_number(arg, args.next())?,
+            "--max-depth" => max_depth = Some(parse_number(arg, args.next())?),
+            "--opt" => match parse_number(arg, args.next())? {
+                level @ 0..=3 => opt_level = Some(level as u32),
+                level => {
+                    return Err(format!(
+                        "ERROR: expected an optimisation level from 0 to 3, found {}",
+                        level
+                    ))
+                }
+            },
+            "--multicall" => match args.next() {
+                Some(files) => multicall.extend(files.split(",").map(|f| f.to_string())),
+                None => return Err("ERROR: expected files after `--multicall`".to_string()),
+            },
+            a if a.starts_with("--") => {
+                return Err(format!("ERROR: unknown option `{}`", a));
+            }
+            a => {
+                if files.len() == max_files {
+                    return Err(format!("ERROR: unexpected argument `{}`", a));
+                }
+                files.push(a.to_string());
+            }
+        }
+    }
+
+    // `clink run project.tar.gz` runs `main.clink` from inside the archive
+    if max_files == 1 && files.first().map_or(false, |f| is_archive(Path::new(f))) {
+        roots.push(PathBuf::from(files.remove(0)));
+        entry = entry.or(Some("main._".to_string()));
+    }
+
+    Ok(Options {
+        file: files.first().cloned(),
+        files,
+        roots,
+        debug_stack,
+        max_output,
+        multicall,
+        deny_warnings,
+        quiet,
+        strict_case,
+        hex,
+        stack_depth,
+        entry,
+        from_ast,
+        lto,
+        safe_output,
+        eof_byte,
+        max_chain,
+        output_alphabet,
+        packages_only,
+        no_bounds_check,
+        print_resolved,
+        cc,
+        dump_symbols,
+        deps,
+        header,
+        split_modules,
+        embed_hash,
+        input,
+        push_args,
+        args: program_args,
+        packed_stack,
+        strict_stack,
+        check_determinism,
+        trace_format,
+        trace_flag,
+        trace_file,
+        verify_at,
+        filter,
+        detect_loops,
+        lib,
+        peak_stack,
+        instrument,
+        frame,
+        embed_abi,
+        canonicalize,
+        verbose,
+        compiled,
+        emit_ir,
+        emit_asm,
+        all,
+        opt_level,
+        emit_bc,
+        bc,
+        target,
+        output,
+        stack_size,
+        max_depth,
+    })
+}
+
+/// Reads an optimisation level written `O0` to `O3`.
+fn parse_opt_level(level: &str) -> Result<u32, String> {
+    match level {
+        "O0" => Ok(0),
+        "O1" => Ok(1),
+        "O2" => Ok(2),
+        "O3" => Ok(3),
+        _ => Err(format!(
+            "ERROR: expected an optimisation level from `O0` to `O3`, found `{}`",
+            level
+        )),
+    }
+}
+
+/// Reads a string of bits, most significant first, as bytes.
+fn parse_bits(bits: &str) -> Result<Vec<u8>, String> {
+    if !bits.chars().all(|c| c == '0' || c == '1') {
+        return Err(format!("ERROR: expected only 0s and 1s, found `{}`", bits));
+    }
+    if bits.len() % 8 != 0 {
+        return Err(format!(
+            "ERROR: expected a whole number of bytes, found {} bits",
+            bits.len()
+        ));
+    }
+    Ok((0..bits.len())
+        .step_by(8)
+        .map(|i| u8::from_str_radix(&bits[i..i + 8], 2).unwrap())
+        .collect())
+}
+
+fn parse_number(option: &str, value: Option<&String>) -> Result<usize, String> {
+    match value {
+        Some(value) => match value.strip_prefix("0x") {
+            Some(hex) => usize::from_str_radix(hex, 16),
+            None => value.parse(),
+        }
+        .map_err(|_| format!("ERROR: expected a number after `{}`, found `{}`", option, value)),
+        None => Err(format!("ERROR: expected a number after `{}`", option)),
+    }
+}
+
+/// Works out the fully-qualified name of the `_` entry function in the given file.
+fn entry_path(file: &Option<String>, roots: &[PathBuf]) -> Result<Vec<String>, String> {
+    let file = file.as_ref().ok_or("ERROR: expected file".to_string())?;
+    // only the directory is resolved, since the file itself can be a link out of its root
+    let path = Path::new(file);
+    let dir = match path.parent() {
+        Some(dir) if dir != Path::new("") => dir,
+        _ => Path::new("."),
+    };
+    let path = match (fs::canonicalize(dir), path.file_name()) {
+        (Ok(dir), Some(name)) if path.exists() => {
+            let path = dir.join(name);
+            // package names are relative to the root the file was scanned from, whichever way
+            // either was written
+            let cwd =
+                current_dir().map_err(|_| "ERROR: cannot find current directory".to_string())?;
+            let roots: Vec<PathBuf> = if roots.is_empty() {
+                vec![cwd]
+            } else {
+                roots.iter().filter_map(|root| fs::canonicalize(cwd.join(root)).ok()).collect()
+            };
+            roots
+                .iter()
+                .find_map(|root| path.strip_prefix(root).ok())
+                .ok_or(format!("ERROR: `{}` is not inside any root", file))?
+                .to_path_buf()
+        }
+        // a file that's only inside an archive root is named from that root
+        _ if path.is_relative() => path.to_path_buf(),
+        _ => return Err(format!("ERROR: file `{}` not found", file)),
+    };
 
     let mut vec_path = Vec::new();
     for component in path.with_extension("").components() {
         if let Component::Normal(x) = component {
             match x.to_str() {
                 Some(x) => vec_path.push(x.to_string()),
-                None => {
-                    println!("ERROR: string read error");
-                    return;
-                },
+                None => return Err("ERROR: string read error".to_string()),
             }
         }
     }
     vec_path.push("_".to_string());
 
-    let program = parse(&mut vec_path);
+    Ok(vec_path)
+}
 
-    if let Err(e) = program {
-        println!("{}", e);
+/// Prints any warnings, returning whether it is fine to carry on.
+fn report_warnings(warnings: &Vec<ParseWarning>, options: &Options) -> bool {
+    if !options.quiet {
+        for warning in warnings {
+            eprintln!("{}", warning);
+        }
+    }
+    if options.deny_warnings && !warnings.is_empty() {
+        eprintln!("ERROR: aborting due to warnings");
+        return false;
+    }
+    if options.strict_case
+        && warnings
+            .iter()
+            .any(|w| matches!(w, ParseWarning::CaseCollision(..)))
+    {
+        eprintln!("ERROR: aborting due to names that differ only by case");
+        return false;
+    }
+    true
+}
+
+/// Works out the entry function, either given directly with `--entry` or from the file.
+fn entry(options: &Options) -> Result<Vec<String>, String> {
+    match &options.entry {
+        Some(entry) => Ok(entry.split(".").map(|x| x.to_string()).collect()),
+        None => entry_path(&options.file, &options.roots),
+    }
+}
+
+/// Formats a parse error, with the extra detail `--verbose` asks for.
+fn parse_error(e: &ParseError, options: &Options) -> String {
+    if options.verbose {
+        format!("{:#}", e)
+    } else {
+        e.to_string()
+    }
+}
+
+/// Reports what parsing found, returning whether it is fine to carry on.
+fn report_parse(report: &ParseReport, options: &Options) -> bool {
+    if options.print_resolved {
+        let mut resolutions: Vec<_> = report.resolutions.iter().collect();
+        resolutions.sort_by(|a, b| a.function.cmp(&b.function));
+        for resolution in resolutions {
+            eprintln!(
+                "{}: {} -> {}",
+                resolution.function.join("."),
+                resolution.reference.join("."),
+                resolution.target.join(".")
+            );
+        }
+    }
+    report_warnings(&report.warnings, options)
+}
+
+fn run(options: &Options) {
+    if let Some(file) = &options.bc {
+        run_bc(file, options);
         return;
     }
 
-    let result = interpret(&(program.unwrap()), vec_path);
+    let mut vec_path = match entry(options) {
+        Ok(vec_path) => vec_path,
+        Err(e) => {
+            eprintln!("{}", e);
+            process::exit(1);
+        }
+    };
+
+    let program = match &options.from_ast {
+        Some(file) => match fs::read_to_string(file) {
+            Ok(json) => program_from_json(&json),
+            Err(_) => {
+                eprintln!("ERROR: file `{}` not found", file);
+                process::exit(1);
+            }
+        },
+        None => {
+            let mut report = ParseReport::default();
+            let program = parse(&options.roots, &options.filter, &mut vec_path, &mut report);
+            if program.is_ok() && !report_parse(&report, options) {
+                process::exit(1);
+            }
+            program
+        }
+    };
+
+    let program = match program {
+        Ok(program) => prepare(program, options),
+        Err(e) => {
+            eprintln!("{}", parse_error(&e, options));
+            process::exit(1);
+        }
+    };
+
+    if options.compiled {
+        match run_compiled(program.clone(), vec_path.clone(), options) {
+            Ok(0) => return,
+            Ok(status) => process::exit(status),
+            Err(CompileError::JitUnavailable(e)) => {
+                if !options.quiet {
+                    eprintln!("WARNING: cannot JIT compile ({}), so interpreting instead", e)
+                }
+            }
+            Err(e) => {
+                eprintln!("{}", e);
+                process::exit(1);
+            }
+        }
+    }
+
+    let interpret_options = interpret_options(options);
 
-    if let Err(e) = result {
-        println!("{}", e);
+    if options.check_determinism {
+        check_determinism(&program, vec_path, &interpret_options);
         return;
     }
+
+    let result = if options.trace_format.is_some() {
+        trace(&program, vec_path, &interpret_options, options)
+    } else {
+        interpret(&program, vec_path, &interpret_options)
+    };
+
+    match result {
+        Ok(finished) => {
+            if options.peak_stack {
+                eprintln!("peak stack: {} bits", finished.peak_stack);
+            }
+        }
+        Err(e) => {
+            eprintln!("{}", e);
+            process::exit(1);
+        }
+    }
 }
 
-fn do_compile(file: &String) {
-    let path = Path::new(file).to_path_buf();
+/// Exits with an error if any option only the interpreter supports is used along with `mode`.
+fn reject_interpreter_only(mode: &str, options: &Options) {
+    let interpreter_only = [
+        (options.trace_format.is_some(), options.trace_flag),
+        (options.check_determinism, "--check-determinism"),
+        (options.peak_stack, "--peak-stack"),
+        (options.detect_loops, "--detect-loops"),
+        (options.strict_stack, "--strict-stack"),
+        (options.packed_stack, "--packed-stack"),
+        (options.hex, "--hex"),
+        (options.safe_output, "--safe-output"),
+        (options.max_output != 0, "--max-output"),
+        (options.max_depth.is_some(), "--max-depth"),
+    ];
+    if let Some((_, option)) = interpreter_only.iter().find(|(used, _)| *used) {
+        eprintln!("ERROR: `{}` can't be used with `{}`", mode, option);
+        eprintln!("HINT:  only the interpreter supports it");
+        process::exit(1);
+    }
+}
 
-    let mut vec_path = Vec::new();
-    for component in path.with_extension("").components() {
-        if let Component::Normal(x) = component {
-            match x.to_str() {
-                Some(x) => vec_path.push(x.to_string()),
-                None => {
-                    println!("ERROR: string read error");
-                    return;
-                },
+/// Runs a program built with `--emit-bc` for `run --bc`. Everything about how it runs, other than
+/// its arguments, was fixed when it was built.
+fn run_bc(file: &str, options: &Options) {
+    reject_interpreter_only("--bc", options);
+    // anything already printed has to come out before the program's own output
+    let _ = io::stdout().flush();
+    match run_bitcode(Path::new(file), &options.args, options.opt_level.unwrap_or(3)) {
+        Ok(0) => {}
+        Ok(status) => process::exit(status),
+        Err(e) => {
+            eprintln!("{}", e);
+            process::exit(1);
+        }
+    }
+}
+
+/// Runs the program compiled in memory for `run --compiled`, with the same input, output and
+/// arguments the interpreter would have.
+fn run_compiled(
+    program: HashMap<Vec<String>, Vec<AST>>,
+    entry: Vec<String>,
+    options: &Options,
+) -> Result<i32, CompileError> {
+    reject_interpreter_only("--compiled", options);
+
+    let compile_options = CompileOptions {
+        debug_stack: options.debug_stack,
+        eof_byte: options.eof_byte,
+        no_bounds_check: options.no_bounds_check,
+        stack_size: options.stack_size,
+        instrument: options.instrument,
+        frame: options.frame,
+        push_args: !options.args.is_empty(),
+        opt_level: options.opt_level,
+        ..Default::default()
+    };
+    // anything already printed has to come out before the program's own output
+    let _ = io::stdout().flush();
+    run_jit(program, entry, &options.args, &compile_options)
+}
+
+/// Applies `--canonicalize` to a program about to be run or built.
+fn prepare(
+    program: HashMap<Vec<String>, Vec<AST>>,
+    options: &Options,
+) -> HashMap<Vec<String>, Vec<AST>> {
+    if !options.canonicalize {
+        return program;
+    }
+    program
+        .into_iter()
+        .map(|(name, asts)| (name, canonicalize(asts)))
+        .collect()
+}
+
+fn interpret_options(options: &Options) -> InterpretOptions {
+    InterpretOptions {
+        max_output: options.max_output,
+        max_depth: options.max_depth.unwrap_or(DEFAULT_MAX_DEPTH),
+        hex: options.hex,
+        safe_output: options.safe_output,
+        eof_byte: options.eof_byte,
+        args: options.args.join(" ").into_bytes(),
+        packed_stack: options.packed_stack,
+        strict_stack: options.strict_stack,
+        detect_loops: options.detect_loops,
+        frame: options.frame,
+        trace_format: options.trace_format.unwrap_or_default(),
+    }
+}
+
+/// Runs the program like `interpret`, writing a trace event for every node to `--trace-file`, or
+/// stderr if there isn't one.
+fn trace(
+    program: &HashMap<Vec<String>, Vec<AST>>,
+    entry: Vec<String>,
+    interpret_options: &InterpretOptions,
+    options: &Options,
+) -> Result<Finished, RuntimeError> {
+    let mut trace: Box<dyn Write> = match &options.trace_file {
+        Some(file) => match fs::File::create(file) {
+            Ok(f) => Box::new(io::BufWriter::new(f)),
+            Err(e) => return Err(RuntimeError::TraceError(format!("`{}`: {}", file, e))),
+        },
+        None => Box::new(io::BufWriter::new(io::stderr().lock())),
+    };
+    let mut input = io::stdin().lock();
+    let mut output = io::BufWriter::new(io::stdout().lock());
+    interpret_with_io(
+        program,
+        entry,
+        interpret_options,
+        &mut input,
+        &mut output,
+        Some(&mut *trace),
+    )
+}
+
+/// Runs the program twice on the same input, printing its output only if both runs agree.
+fn check_determinism(
+    program: &HashMap<Vec<String>, Vec<AST>>,
+    entry: Vec<String>,
+    options: &InterpretOptions,
+) {
+    let mut input = Vec::new();
+    if let Err(e) = io::stdin().read_to_end(&mut input) {
+        eprintln!("ERROR: cannot read input: {}", e);
+        process::exit(1);
+    }
+
+    let mut outputs = Vec::new();
+    for _ in 0..2 {
+        let mut output = Vec::new();
+        let result = interpret_with_io(
+            program,
+            entry.clone(),
+            options,
+            &mut input.as_slice(),
+            &mut output,
+            None,
+        );
+        if let Err(e) = result {
+            eprintln!("{}", e);
+            process::exit(1);
+        }
+        outputs.push(output);
+    }
+
+    let (a, b) = (&outputs[0], &outputs[1]);
+    match first_difference(a, b) {
+        Some(i) => {
+            eprintln!(
+                "ERROR: runs differ at byte {}: the first printed {}, the second {}",
+                i,
+                byte_at(a, i),
+                byte_at(b, i)
+            );
+            process::exit(1);
+        }
+        None => {
+            if let Err(e) = io::stdout().write_all(a) {
+                eprintln!("ERROR: cannot write output: {}", e);
+                process::exit(1);
             }
         }
     }
-    vec_path.push("_".to_string());
+}
+
+fn do_compile(options: &Options) {
+    if options.push_args && !options.multicall.is_empty() {
+        eprintln!("ERROR: `--push-args` can't be used with `--multicall`");
+        eprintln!("HINT:  multicall binaries use their first argument to pick the program");
+        process::exit(1);
+    }
+    if options.instrument && options.split_modules {
+        eprintln!("ERROR: `--instrument` can't be used with `--split-modules`");
+        process::exit(1);
+    }
+    if options.embed_abi
+        && (options.push_args || !options.multicall.is_empty() || options.frame.is_some())
+    {
+        eprintln!("ERROR: `--embed-abi` can't be used with `--push-args`, `--multicall` or `--frame`");
+        eprintln!("HINT:  the entry takes its input as an argument and returns its output");
+        process::exit(1);
+    }
+    if options.lib && (options.push_args || !options.multicall.is_empty()) {
+        eprintln!("ERROR: `--lib` can't be used with `--push-args` or `--multicall`");
+        eprintln!("HINT:  a library has no `main` of its own to handle arguments");
+        process::exit(1);
+    }
+    if options.emit_bc && (options.lib || options.embed_abi || options.split_modules) {
+        eprintln!("ERROR: `--emit-bc` can't be used with `--lib`, `--embed-abi` or `--split-modules`");
+        eprintln!("HINT:  the bitcode has to hold a whole program, `main` and all");
+        process::exit(1);
+    }
+    if options.lib && options.frame.is_some() {
+        eprintln!("ERROR: `--frame` can't be used with `--lib`");
+        eprintln!("HINT:  the output belongs to whatever program links the library");
+        process::exit(1);
+    }
+
+    let mut multicall = Vec::new();
+    for file in &options.multicall {
+        match entry_path(&Some(file.clone()), &options.roots) {
+            Ok(vec_path) => {
+                // dispatch on the file name, without its package or entry function
+                let name = vec_path[vec_path.len() - 2].clone();
+                multicall.push((name, vec_path));
+            }
+            Err(e) => {
+                eprintln!("{}", e);
+                process::exit(1);
+            }
+        }
+    }
+
+    let vec_path = match multicall.first() {
+        Some((_, vec_path)) if options.file.is_none() => vec_path.clone(),
+        _ => match entry(options) {
+            Ok(vec_path) => vec_path,
+            Err(e) => {
+                eprintln!("{}", e);
+                process::exit(1);
+            }
+        },
+    };
+
+    let mut entries = vec![vec_path.clone()];
+    entries.extend(multicall.iter().map(|(_, e)| e.clone()));
 
-    let program = parse(&mut vec_path);
+    let mut report = ParseReport::default();
+    let program = parse_entries(&options.roots, &options.filter, &entries, &mut report);
 
     if let Err(e) = program {
-        println!("{}", e);
+        eprintln!("{}", parse_error(&e, options));
+        process::exit(1);
+    }
+
+    if !report_parse(&report, options) {
+        process::exit(1);
+    }
+
+    let source_hash = if options.embed_hash {
+        Some(hash_sources(&report.files_read))
+    } else {
+        None
+    };
+
+    let compile_options = CompileOptions {
+        debug_stack: options.debug_stack,
+        multicall,
+        lto: options.lto,
+        eof_byte: options.eof_byte,
+        no_bounds_check: options.no_bounds_check,
+        cc: options.cc.clone(),
+        dump_symbols: options.dump_symbols,
+        push_args: options.push_args,
+        header: options.header.clone(),
+        split_modules: options.split_modules,
+        source_hash,
+        verify_at: options.verify_at.clone(),
+        lib: options.lib || options.embed_abi,
+        instrument: options.instrument,
+        frame: options.frame,
+        embed_abi: options.embed_abi,
+        emit_ir: options.emit_ir,
+        emit_asm: options.emit_asm,
+        opt_level: options.opt_level,
+        emit_bc: options.emit_bc,
+        target: options.target.clone(),
+        output: options.output.clone(),
+        stack_size: options.stack_size,
+    };
+
+    let module_name = current_dir().unwrap().file_name().unwrap().to_str().unwrap().to_string();
+
+    if let Some(deps) = &options.deps {
+        // make needs spaces in names escaped
+        let escape = |s: &str| s.replace(' ', "\\ ");
+        let target = if let Some(output) = &options.output {
+            output.clone()
+        } else if options.lib || options.embed_abi {
+            format!("lib{}.a", module_name)
+        } else {
+            module_name.clone()
+        };
+        let mut rule = escape(&target) + ":";
+        // a file read from an archive is only there as the archive
+        let mut listed = Vec::new();
+        for file in &report.files_read {
+            if !listed.contains(&file.on_disk) {
+                rule += " ";
+                rule += &escape(&file.on_disk);
+                listed.push(file.on_disk.clone());
+            }
+        }
+        rule += "\n";
+        if let Err(e) = fs::write(deps, rule) {
+            eprintln!("ERROR: cannot write `{}`: {}", deps, e);
+            process::exit(1);
+        }
+    }
+
+    if let Err(e) = compile(
+        &module_name,
+        prepare(program.unwrap(), options),
+        vec_path,
+        &compile_options,
+    ) {
+        eprintln!("{}", e);
+        process::exit(1);
+    }
+}
+
+fn specialize_program(options: &Options) {
+    let mut vec_path = match entry(options) {
+        Ok(vec_path) => vec_path,
+        Err(e) => {
+            eprintln!("{}", e);
+            process::exit(1);
+        }
+    };
+
+    let mut report = ParseReport::default();
+    let program = match parse(&options.roots, &options.filter, &mut vec_path, &mut report) {
+        Ok(program) => program,
+        Err(e) => {
+            eprintln!("{}", parse_error(&e, options));
+            process::exit(1);
+        }
+    };
+
+    if !report_parse(&report, options) {
+        process::exit(1);
+    }
+
+    let input = match parse_bits(options.input.as_deref().unwrap_or("")) {
+        Ok(input) => input,
+        Err(e) => {
+            eprintln!("{}", e);
+            process::exit(1);
+        }
+    };
+
+    match specialize(&program, &vec_path, &input) {
+        Some(program) => println!("{}", program_to_json(&prepare(program, options))),
+        None => {
+            eprintln!("ERROR: no such function {}", vec_path.join("."));
+            process::exit(1);
+        }
+    }
+}
+
+fn diff(options: &Options) {
+    if options.files.len() != 2 {
+        eprintln!("ERROR: expected two files to compare");
+        process::exit(1);
+    }
+
+    let input = match &options.input {
+        Some(file) => match fs::read(file) {
+            Ok(input) => input,
+            Err(_) => {
+                eprintln!("ERROR: file `{}` not found", file);
+                process::exit(1);
+            }
+        },
+        None => Vec::new(),
+    };
+
+    let interpret_options = interpret_options(options);
+
+    let mut outputs = Vec::new();
+    for file in &options.files {
+        let mut vec_path = match entry_path(&Some(file.clone()), &options.roots) {
+            Ok(vec_path) => vec_path,
+            Err(e) => {
+                eprintln!("{}", e);
+                process::exit(1);
+            }
+        };
+
+        let mut report = ParseReport::default();
+        let program = match parse(&options.roots, &options.filter, &mut vec_path, &mut report) {
+            Ok(program) => program,
+            Err(e) => {
+                eprintln!("{}", parse_error(&e, options));
+                process::exit(1);
+            }
+        };
+        if !report_parse(&report, options) {
+            process::exit(1);
+        }
+
+        let mut output = Vec::new();
+        let result = interpret_with_io(
+            &program,
+            vec_path,
+            &interpret_options,
+            &mut input.as_slice(),
+            &mut output,
+            None,
+        );
+        if let Err(e) = result {
+            eprintln!("{}: {}", file, e);
+            process::exit(1);
+        }
+        outputs.push(output);
+    }
+
+    let (a, b) = (&outputs[0], &outputs[1]);
+    match first_difference(a, b) {
+        Some(i) => {
+            println!(
+                "outputs differ at byte {}: {} printed {}, {} printed {}",
+                i,
+                options.files[0],
+                byte_at(a, i),
+                options.files[1],
+                byte_at(b, i)
+            );
+            process::exit(1);
+        }
+        None => println!("outputs match ({} bytes)", a.len()),
+    }
+}
+
+/// Runs every `%test` in the project, exiting with an error if any fail.
+fn test(options: &Options) {
+    let mut project = match scan(&options.roots, &options.filter) {
+        Ok(project) => project,
+        Err(e) => {
+            eprintln!("{}", e);
+            process::exit(1);
+        }
+    };
+
+    let tests = std::mem::take(&mut project.tests);
+    let entries: Vec<Vec<String>> = tests.iter().map(|t| t.function.clone()).collect();
+    let mut report = ParseReport::default();
+    let program = match parse_project(project, &entries, &mut report) {
+        Ok(program) => program,
+        Err(e) => {
+            eprintln!("{}", parse_error(&e, options));
+            process::exit(1);
+        }
+    };
+    if !report_parse(&report, options) {
+        process::exit(1);
+    }
+
+    let interpret_options = interpret_options(options);
+    let mut failed = 0;
+    for test in &tests {
+        let mut output = Vec::new();
+        let result = interpret_with_io(
+            &program,
+            test.function.clone(),
+            &interpret_options,
+            &mut test.input.as_slice(),
+            &mut output,
+            None,
+        );
+        let name = format!("{} in `{}`", test.function.join("."), test.source);
+        match result {
+            Ok(_) if output == test.output => println!("PASS {}", name),
+            Ok(_) => {
+                failed += 1;
+                println!(
+                    "FAIL {}: expected \"{}\", got \"{}\"",
+                    name,
+                    test.output.escape_ascii(),
+                    output.escape_ascii()
+                );
+            }
+            Err(e) => {
+                failed += 1;
+                println!("FAIL {}\n{}", name, e);
+            }
+        }
+    }
+
+    println!("{} passed, {} failed", tests.len() - failed, failed);
+    if failed > 0 {
+        process::exit(1);
+    }
+}
+
+/// Interprets a snippet of clink given on the command line, with `--input` as its input written in
+/// binary like `specialize` takes it, then prints its output and the stack it finished with as 1s
+/// and 0s, top last.
+fn eval_bits(options: &Options) {
+    let body = match &options.file {
+        Some(body) => body,
+        None => {
+            eprintln!("ERROR: expected some clink to evaluate");
+            process::exit(1);
+        }
+    };
+    let asts = match parse_body(body) {
+        Ok(asts) => asts,
+        Err(e) => {
+            eprintln!("{}", e);
+            process::exit(1);
+        }
+    };
+
+    let entry = vec!["_".to_string()];
+    let program = HashMap::from([(entry.clone(), asts)]);
+    let input = match parse_bits(options.input.as_deref().unwrap_or("")) {
+        Ok(input) => input,
+        Err(e) => {
+            eprintln!("{}", e);
+            process::exit(1);
+        }
+    };
+    let mut output = Vec::new();
+    let result = interpret_with_io(
+        &program,
+        entry,
+        &interpret_options(options),
+        &mut input.as_slice(),
+        &mut output,
+        None,
+    );
+    let stack = match result {
+        Ok(finished) => finished.stack,
+        Err(e) => {
+            eprintln!("{}", e);
+            process::exit(1);
+        }
+    };
+
+    let bits: String = stack.iter().map(|&b| if b { '1' } else { '0' }).collect();
+    println!("output: {}", output.escape_ascii());
+    println!("stack:  {}", bits);
+}
+
+fn first_difference(a: &[u8], b: &[u8]) -> Option<usize> {
+    (0..a.len().max(b.len())).find(|&i| a.get(i) != b.get(i))
+}
+
+/// Describes the byte printed at `i`, if the output got that far.
+fn byte_at(output: &[u8], i: usize) -> String {
+    match output.get(i) {
+        Some(b) => format!("0x{:02x}", b),
+        None => "nothing".to_string(),
+    }
+}
+
+/// Hashes every source file's path and contents, as they were read, with 64-bit FNV-1a, in a
+/// stable order.
+fn hash_sources(files: &[SourceFile]) -> String {
+    let mut files: Vec<&SourceFile> = files.iter().collect();
+    files.sort_by(|a, b| a.path.cmp(&b.path));
+
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for file in files {
+        // separate the parts so moving bytes between them changes the hash
+        for part in [file.path.as_bytes(), &[0], file.content.as_bytes(), &[0]] {
+            for byte in part {
+                hash ^= *byte as u64;
+                hash = hash.wrapping_mul(0x100000001b3);
+            }
+        }
+    }
+    format!("{:016x}", hash)
+}
+
+/// Parses the program without running it, for `check`. Anything wrong exits with status 1, so
+/// a clean check prints nothing unless asked to.
+fn check(options: &Options) {
+    if options.packages_only {
+        match scan(&options.roots, &options.filter) {
+            Ok(project) => {
+                if !report_warnings(&project.warnings, options) {
+                    process::exit(1);
+                }
+            }
+            Err(e) => {
+                eprintln!("{}", e);
+                process::exit(1);
+            }
+        }
         return;
     }
 
-    compile(current_dir().unwrap().file_name().unwrap().to_str().unwrap(), program.unwrap(), vec_path);
-}
\ No newline at end of file
+    if options.all {
+        check_every_function(options);
+        // there's nothing more to check without an entry
+        if options.file.is_none() && options.entry.is_none() {
+            return;
+        }
+    }
+
+    let mut vec_path = match entry(options) {
+        Ok(vec_path) => vec_path,
+        Err(e) => {
+            eprintln!("{}", e);
+            process::exit(1);
+        }
+    };
+
+    let mut report = ParseReport::default();
+    let program = parse(&options.roots, &options.filter, &mut vec_path, &mut report);
+
+    let program = match program {
+        Ok(program) => program,
+        Err(e) => {
+            eprintln!("{}", parse_error(&e, options));
+            process::exit(1);
+        }
+    };
+    // running would only find this out once it started
+    if !program.contains_key(&vec_path) {
+        eprintln!("ERROR: no such function {}", vec_path.join("."));
+        process::exit(1);
+    }
+
+    for dead in dead_code(&program) {
+        report
+            .warnings
+            .push(ParseWarning::DeadCode(dead.function, dead.call, dead.node));
+    }
+
+    if !report_parse(&report, options) {
+        process::exit(1);
+    }
+
+    if options.stack_depth {
+        match max_stack_depth(&program, &vec_path) {
+            StackDepth::Bounded(depth) => {
+                println!("max stack depth: {} bits", depth);
+                match options.stack_size {
+                    Some(stack_size) if depth > stack_size.into() && !options.quiet => {
+                        eprintln!(
+                            "WARNING: exceeds the compiled stack size of {} bits",
+                            stack_size
+                        );
+                        eprintln!("HINT:  build with a bigger `--stack-size`, or none at all");
+                    }
+                    _ => {}
+                }
+            }
+            StackDepth::Unbounded(id) => {
+                println!("max stack depth: unbounded (`{}` is recursive)", id.join("."));
+            }
+        }
+    }
+
+    if options.max_chain {
+        let chain = longest_call_chain(&program, &vec_path);
+        let names: Vec<String> = chain.longest.iter().map(|f| f.join(".")).collect();
+        println!(
+            "longest call chain ({} functions): {}",
+            names.len(),
+            names.join(" -> ")
+        );
+        for f in chain.recursive {
+            println!("recursive: {}", f.join("."));
+        }
+    }
+
+    if options.output_alphabet {
+        match output_alphabet(&program, &vec_path) {
+            OutputAlphabet::Bytes(bytes) => {
+                let text: Vec<u8> = bytes.iter().copied().collect();
+                println!("output alphabet: \"{}\"", text.escape_ascii());
+                let unprintable: Vec<u8> = bytes
+                    .into_iter()
+                    .filter(|b| !(b.is_ascii_graphic() || b" \n\t".contains(b)))
+                    .collect();
+                if !unprintable.is_empty() && !options.quiet {
+                    eprintln!(
+                        "WARNING: can print bytes other than printable ASCII: \"{}\"",
+                        unprintable.escape_ascii()
+                    );
+                }
+            }
+            OutputAlphabet::DependsOnInput => println!("output alphabet: unknown (depends on input)"),
+            OutputAlphabet::TooComplex => {
+                println!("output alphabet: unknown (too many states to follow)")
+            }
+        }
+    }
+}
+
+/// Resolves every function in the project for `check --all`, exiting with an error if any fail.
+fn check_every_function(options: &Options) {
+    let project = match scan(&options.roots, &options.filter) {
+        Ok(project) => project,
+        Err(e) => {
+            eprintln!("{}", parse_error(&e, options));
+            process::exit(1);
+        }
+    };
+    let mut report = ParseReport::default();
+    let errors = match check_all(project, &mut report) {
+        Ok(errors) => errors,
+        Err(e) => {
+            eprintln!("{}", parse_error(&e, options));
+            process::exit(1);
+        }
+    };
+    // the warnings come up again when checking the entry
+    if options.file.is_none() && options.entry.is_none() && !report_parse(&report, options) {
+        process::exit(1);
+    }
+
+    for e in &errors {
+        eprintln!("in `{}`, defined in `{}`:", e.function.join("."), e.source);
+        eprintln!("{}", parse_error(&e.error, options));
+    }
+    if !errors.is_empty() {
+        eprintln!("ERROR: {} of the project's functions failed to resolve", errors.len());
+        process::exit(1);
+    }
+}
+
+fn print_program(options: &Options) {
+    let mut vec_path = match entry(options) {
+        Ok(vec_path) => vec_path,
+        Err(e) => {
+            eprintln!("{}", e);
+            process::exit(1);
+        }
+    };
+
+    let mut report = ParseReport::default();
+    let program = match parse(&options.roots, &options.filter, &mut vec_path, &mut report) {
+        Ok(program) => program,
+        Err(e) => {
+            eprintln!("{}", parse_error(&e, options));
+            process::exit(1);
+        }
+    };
+
+    if !report_parse(&report, options) {
+        process::exit(1);
+    }
+
+    let program = prepare(program, options);
+    let mut names: Vec<&Vec<String>> = program.keys().collect();
+    names.sort();
+    for name in names {
+        println!("{}", name.join("."));
+        print_asts(&program[name], 1);
+    }
+}
+
+/// Prints each node on its own line, named the same as in a trace, with the branches of a split
+/// and the contents of a bracket indented below it.
+fn print_asts(asts: &[AST], depth: usize) {
+    let indent = "  ".repeat(depth);
+    for ast in asts {
+        match ast {
+            AST::Left => println!("{}left", indent),
+            AST::Right => println!("{}right", indent),
+            AST::Print => println!("{}print", indent),
+            AST::Read => println!("{}read", indent),
+            AST::Split(l, r) => {
+                println!("{}split", indent);
+                println!("{}  if left:", indent);
+                print_asts(l, depth + 2);
+                println!("{}  if right:", indent);
+                print_asts(r, depth + 2);
+            }
+            AST::Bracketed(asts) => {
+                println!("{}bracket", indent);
+                print_asts(asts, depth + 1);
+            }
+            AST::Id(id) => println!("{}call {}", indent, id.join(".")),
+            AST::TailCall(id) => println!("{}tail_call {}", indent, id.join(".")),
+        }
+    }
+}
+
+fn tree(options: &Options) {
+    let project = scan(&options.roots, &options.filter);
+
+    if let Err(e) = project {
+        eprintln!("{}", e);
+        process::exit(1);
+    }
+
+    print_tree(&project.unwrap(), &mut Vec::new(), 0);
+}
+
+fn print_tree(project: &Project, pkg: &mut Vec<String>, depth: usize) {
+    let is_below = |pkg: &Vec<String>| {
+        project
+            .files
+            .iter()
+            .any(|f| f.len() > pkg.len() && f.starts_with(pkg))
+    };
+
+    let children: BTreeSet<String> = project
+        .files
+        .iter()
+        .filter(|f| f.len() > pkg.len() && f.starts_with(pkg))
+        .map(|f| f[pkg.len()].clone())
+        .collect();
+
+    for child in children {
+        pkg.push(child.clone());
+        let indent = "  ".repeat(depth);
+
+        if project.files.contains(pkg) {
+            let funcs: BTreeSet<&String> = project
+                .functions
+                .keys()
+                .filter(|f| f.len() == pkg.len() + 1 && f.starts_with(pkg))
+                .map(|f| f.last().unwrap())
+                .collect();
+            let funcs: Vec<&str> = funcs.into_iter().map(|f| f.as_str()).collect();
+            println!("{}{}.clink: {}", indent, child, funcs.join(" "));
+        }
+
+        if is_below(pkg) {
+            println!("{}{}/", indent, child);
+            print_tree(project, pkg, depth + 1);
+        }
+
+        pkg.pop();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry_of(file: &Path, roots: &[PathBuf]) -> Vec<String> {
+        entry_path(&Some(file.display().to_string()), roots).unwrap()
+    }
+
+    #[test]
+    fn relative_file_is_named_from_its_root() {
+        // tests run from the crate's own directory
+        let roots = [PathBuf::from("src")];
+        assert_eq!(entry_of(Path::new("src/main.rs"), &roots), ["main", "_"]);
+        assert_eq!(entry_of(Path::new("src/../src/main.rs"), &roots), ["main", "_"]);
+        assert_eq!(entry_of(Path::new("src/main.rs"), &[]), ["src", "main", "_"]);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn absolute_file_is_named_from_its_root() {
+        /// A directory removed once it's dropped, even if the test panics.
+        struct Scratch(PathBuf);
+
+        impl Drop for Scratch {
+            fn drop(&mut self) {
+                let _ = fs::remove_dir_all(&self.0);
+            }
+        }
+
+        let scratch = Scratch(std::env::temp_dir().join(format!("clink-entry-{}", process::id())));
+        let dir = &scratch.0;
+        // a run that was killed leaves its directory, and the link in it, behind
+        let _ = fs::remove_dir_all(dir);
+        let root = dir.join("root");
+        fs::create_dir_all(root.join("pkg")).unwrap();
+        fs::write(root.join("pkg").join("main.clink"), "_ ;").unwrap();
+        std::os::unix::fs::symlink(&root, dir.join("link")).unwrap();
+
+        let file = root.join("pkg").join("main.clink");
+        let dotted = root.join("pkg").join("..").join("pkg").join("main.clink");
+        let linked = dir.join("link").join("pkg").join("main.clink");
+        let roots = [root.clone()];
+        let entries = [
+            entry_of(&file, &roots),
+            entry_of(&dotted, &roots),
+            entry_of(&linked, &roots),
+            entry_of(&file, &[dir.join("link")]),
+        ];
+        for entry in entries {
+            assert_eq!(entry, ["pkg", "main", "_"]);
+        }
+        assert!(entry_path(&Some(file.display().to_string()), &[root.join("none")]).is_err());
+    }
+}
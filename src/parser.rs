@@ -1,13 +1,17 @@
 use std::{
-    collections::{HashMap, HashSet},
+    collections::{BTreeMap, HashMap, HashSet},
     env::current_dir,
     fmt::Display,
     fs,
+    io::Read,
     iter::Peekable,
-    path::Path,
+    path::{Component, Path, PathBuf},
     str::Chars,
 };
 
+use flate2::read::GzDecoder;
+use serde::{Deserialize, Serialize};
+
 #[derive(Debug)]
 pub enum Token {
     Bang,
@@ -16,14 +20,75 @@ pub enum Token {
     Semicolon,
     At,
     Hash,
+    Ampersand,
+    Equals,
     LBracket,
     RBracket,
     Bracket(Vec<Token>),
     Split(Vec<Token>, Vec<Token>),
     Id(Vec<String>),
+    /// A call marked with `^` as one the compiler should make a tail call.
+    Tail(Vec<String>),
+    /// A line starting with `%`, without the `%`.
+    Directive(String),
+}
+
+impl Display for Token {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Token::Bang => write!(f, "!"),
+            Token::Question => write!(f, "?"),
+            Token::Colon => write!(f, ":"),
+            Token::Semicolon => write!(f, ";"),
+            Token::At => write!(f, "@"),
+            Token::Hash => write!(f, "#"),
+            Token::Ampersand => write!(f, "&"),
+            Token::Equals => write!(f, "="),
+            Token::LBracket => write!(f, "("),
+            Token::RBracket => write!(f, ")"),
+            Token::Bracket(ts) => write!(f, "({})", join_tokens(ts)),
+            Token::Split(l, r) => write!(f, "{} : {}", join_tokens(l), join_tokens(r)),
+            Token::Id(id) => write!(f, "{}", id.join(".")),
+            Token::Tail(id) => write!(f, "^{}", id.join(".")),
+            Token::Directive(d) => write!(f, "%{}", d),
+        }
+    }
 }
 
-#[derive(Debug, Clone)]
+/// Where a token starts in its file, counting lines and columns from 1.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Span {
+    pub line: usize,
+    pub col: usize,
+}
+
+impl Span {
+    fn start() -> Span {
+        Span { line: 1, col: 1 }
+    }
+
+    /// Moves past `text`.
+    fn advance(&mut self, text: &str) {
+        for c in text.chars() {
+            if c == '\n' {
+                self.line += 1;
+                self.col = 1;
+            } else {
+                self.col += 1;
+            }
+        }
+    }
+}
+
+fn join_tokens(tokens: &Vec<Token>) -> String {
+    tokens
+        .iter()
+        .map(|t| t.to_string())
+        .collect::<Vec<String>>()
+        .join(" ")
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum AST {
     Left,
     Right,
@@ -32,29 +97,80 @@ pub enum AST {
     Split(Vec<AST>, Vec<AST>),
     Bracketed(Vec<AST>),
     Id(Vec<String>),
+    /// A call that's the last thing its function does, which the compiler makes a tail call.
+    /// Otherwise the same as `Id`.
+    TailCall(Vec<String>),
 }
 
 #[derive(Debug)]
 pub enum ParseError {
-    FileNotFound(String),
-    ExpectedPackageName,
+    /// A file that couldn't be read, what led to it, like the root being scanned, and why it
+    /// couldn't be read.
+    FileNotFound(String, String, String),
+    /// No package name after the `!` of an import or the `=` of an alias, given by that character,
+    /// what was found instead (if anything) and the file.
+    ExpectedPackageName(char, Option<Token>, String),
     CannotDefineFunctionOutsidePackage(Vec<String>),
-    FunctionDefinedTwice(String),
-    UnknownFunction(Vec<String>),
+    FunctionDefinedTwice(String, String, String),
+    ContinuesUndefinedFunction(String, String),
+    /// An unresolved reference, along with any packages that define a function by that name and
+    /// every fully-qualified name tried, in order. Those are only shown with the alternate format,
+    /// `{:#}`.
+    UnknownFunction(Vec<String>, Vec<Vec<String>>, Vec<Vec<String>>),
     UnknownPackage(Vec<String>),
-    AmbiguousReference(Vec<String>),
+    AmbiguousReference(Vec<String>, Vec<Vec<String>>),
     UnknownAssociativity,
-    OSStringConversionError,
+    /// A `(` never closed, or a `)` with nothing to close, given as whichever it was.
+    UnbalancedParentheses(char),
+    UnexpectedToken(Token, String),
+    OSStringConversionError(String),
     CannotFindCurrentDir,
-    ErrorReadingDirectory,
+    ErrorReadingDirectory(String),
+    /// An archive given as a root, and why it couldn't be read.
+    ErrorReadingArchive(String, String),
+    /// A `.clink` file and a directory next to it that would both be the same package.
+    PackagePathConflict(String, String),
     CannotGetMetadata,
+    InvalidAst(String),
+    ExpectedFunctionAfterCaret,
+    /// A call marked with `^` that isn't the last thing its function does, as it was written.
+    TailCallNotLast(Vec<String>),
+    /// A `%` directive that isn't a well-formed test, and the file it's in.
+    InvalidTest(String, String),
+    /// An alias declared twice in the same file.
+    AliasDefinedTwice(String, String),
+    /// An alias with the same name as a top-level package, and the file declaring it.
+    AliasShadowsPackage(String, String),
+    /// Another error, and the file and place in it that caused it.
+    At(String, Span, Box<ParseError>),
+}
+
+impl ParseError {
+    fn at(self, path: &str, span: Span) -> ParseError {
+        ParseError::At(path.to_string(), span, Box::new(self))
+    }
 }
 
 impl Display for ParseError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
-            ParseError::FileNotFound(p) => write!(f, "ERROR: file `{}` not found", p),
-            ParseError::ExpectedPackageName => write!(f, "ERROR: expected package name"),
+            ParseError::FileNotFound(p, found_in, e) => write!(
+                f,
+                "ERROR: cannot read file `{}`, found {}: {}",
+                p, found_in, e
+            ),
+            ParseError::ExpectedPackageName(after, found, file) => match found {
+                Some(token) => write!(
+                    f,
+                    "ERROR: expected package name after `{}`, found `{}` in `{}`",
+                    after, token, file
+                ),
+                None => write!(
+                    f,
+                    "ERROR: expected package name after `{}`, found end of file in `{}`",
+                    after, file
+                ),
+            },
             ParseError::CannotDefineFunctionOutsidePackage(id) => {
                 write!(
                     f,
@@ -62,47 +178,237 @@ impl Display for ParseError {
                     id.join(".")
                 )
             }
-            ParseError::UnknownFunction(path) => {
-                write!(f, "ERROR: unknown function {}", path.join("."))
+            ParseError::UnknownFunction(path, candidates, tried) => {
+                write!(f, "ERROR: unknown function {}", path.join("."))?;
+                if f.alternate() && !tried.is_empty() {
+                    let tried: Vec<String> =
+                        tried.iter().map(|t| format!("`{}`", t.join("."))).collect();
+                    write!(
+                        f,
+                        "\nHINT:  looked for {}, in that order, and none exist",
+                        tried.join(", ")
+                    )?;
+                }
+                if !candidates.is_empty() {
+                    let candidates: Vec<String> = candidates
+                        .iter()
+                        .map(|c| format!("`{}`", c.join(".")))
+                        .collect();
+                    write!(
+                        f,
+                        "\nHINT:  did you forget to import {}?",
+                        candidates.join(" or ")
+                    )?;
+                }
+                Ok(())
             }
-            ParseError::AmbiguousReference(id) => {
-                write!(f, "ERROR: ambiguous reference `{}`", id.join("."))
+            ParseError::AmbiguousReference(id, candidates) => {
+                let candidates: Vec<String> = candidates.iter().map(|c| c.join(".")).collect();
+                write!(
+                    f,
+                    "ERROR: ambiguous reference `{}` could be any of: {}",
+                    id.join("."),
+                    candidates.join(", ")
+                )
             }
             ParseError::UnknownAssociativity => write!(f, "ERROR: unknown associativity of `:`"),
-            ParseError::FunctionDefinedTwice(id) => {
-                write!(f, "ERROR: function `{}` defined twice", id)
+            ParseError::UnbalancedParentheses('(') => write!(f, "ERROR: `(` is never closed"),
+            ParseError::UnbalancedParentheses(c) => {
+                write!(f, "ERROR: `{}` doesn't close anything", c)
+            }
+            ParseError::UnexpectedToken(token, file) => {
+                write!(
+                    f,
+                    "ERROR: unexpected `{}` outside a function definition in `{}`",
+                    token, file
+                )
+            }
+            ParseError::FunctionDefinedTwice(id, first, second) => {
+                write!(
+                    f,
+                    "ERROR: function `{}` defined twice (in `{}` and `{}`)",
+                    id, first, second
+                )
+            }
+            ParseError::AliasDefinedTwice(alias, file) => {
+                write!(f, "ERROR: alias `{}` declared twice in `{}`", alias, file)
+            }
+            ParseError::AliasShadowsPackage(alias, file) => {
+                writeln!(
+                    f,
+                    "ERROR: alias `{}` in `{}` has the same name as a package",
+                    alias, file
+                )?;
+                write!(f, "HINT:  rename the alias")
+            }
+            ParseError::ContinuesUndefinedFunction(id, file) => {
+                write!(
+                    f,
+                    "ERROR: cannot continue function `{}` before it is defined in `{}`",
+                    id, file
+                )
             }
             ParseError::UnknownPackage(path) => {
                 write!(f, "ERROR: unknown package {}", path.join("."))
             }
             ParseError::CannotFindCurrentDir => write!(f, "ERROR: cannot find current directory"),
-            ParseError::ErrorReadingDirectory => write!(f, "ERROR: cannot read directory"),
-            ParseError::OSStringConversionError => write!(f, "ERROR: OSStr converstion error"),
+            ParseError::ErrorReadingDirectory(dir) => {
+                write!(f, "ERROR: cannot read directory `{}`", dir)
+            }
+            ParseError::ErrorReadingArchive(archive, e) => {
+                write!(f, "ERROR: cannot read archive `{}`: {}", archive, e)
+            }
+            ParseError::PackagePathConflict(a, b) => {
+                write!(f, "ERROR: `{}` and `{}` are both the same package", a, b)?;
+                write!(f, "\nHINT:  rename one of them")
+            }
+            ParseError::OSStringConversionError(path) => {
+                write!(f, "ERROR: path `{}` is not valid unicode", path)
+            }
             ParseError::CannotGetMetadata => write!(f, "ERROR: cannot get metadata"),
+            ParseError::InvalidAst(e) => write!(f, "ERROR: invalid AST: {}", e),
+            ParseError::ExpectedFunctionAfterCaret => {
+                write!(f, "ERROR: expected a function to call after `^`")
+            }
+            ParseError::TailCallNotLast(id) => {
+                writeln!(
+                    f,
+                    "ERROR: `^{}` is not the last thing its function does",
+                    id.join(".")
+                )?;
+                write!(
+                    f,
+                    "HINT:  only a call with nothing after it can be a tail call"
+                )
+            }
+            ParseError::At(path, span, e) => {
+                write!(f, "{}:{}:{}: ", path, span.line, span.col)?;
+                if f.alternate() {
+                    write!(f, "{:#}", e)
+                } else {
+                    write!(f, "{}", e)
+                }
+            }
+            ParseError::InvalidTest(directive, source) => {
+                writeln!(f, "ERROR: cannot read `%{}` in `{}`", directive, source)?;
+                write!(
+                    f,
+                    "HINT:  tests are written `%test function \"input\" \"expected output\"`"
+                )
+            }
         }
     }
 }
 
-pub fn tokenise(input: &str) -> Result<Vec<Token>, ParseError> {
+#[derive(Debug)]
+pub enum ParseWarning {
+    DuplicateImport(Vec<String>, String),
+    /// Two packages or two functions in the same package whose names differ only by case.
+    CaseCollision(Vec<String>, Vec<String>),
+    /// Code in a function that runs after a call which never returns, and the number of its first
+    /// node as `clink ast` lists them.
+    DeadCode(Vec<String>, Vec<String>, usize),
+}
+
+impl Display for ParseWarning {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ParseWarning::DuplicateImport(pkg, file) => {
+                write!(
+                    f,
+                    "WARNING: package `{}` imported more than once in `{}`",
+                    pkg.join("."),
+                    file
+                )
+            }
+            ParseWarning::CaseCollision(a, b) => {
+                write!(
+                    f,
+                    "WARNING: `{}` and `{}` differ only by case, which isn't portable",
+                    a.join("."),
+                    b.join(".")
+                )
+            }
+            ParseWarning::DeadCode(function, call, node) => {
+                write!(
+                    f,
+                    "WARNING: `{}` never returns, so the code to its left in `{}` never runs, \
+                     from its node {} on, counting as `clink ast` lists them",
+                    call.join("."),
+                    function.join("."),
+                    node
+                )
+            }
+        }
+    }
+}
+
+/// A reference in `function` to `reference`, which resolved to the function `target`.
+pub struct Resolution {
+    pub function: Vec<String>,
+    pub reference: Vec<String>,
+    pub target: Vec<String>,
+}
+
+/// What parsing found besides the program itself.
+#[derive(Default)]
+pub struct ParseReport {
+    pub warnings: Vec<ParseWarning>,
+    pub resolutions: Vec<Resolution>,
+    /// Every file the program was parsed from.
+    pub files_read: Vec<SourceFile>,
+}
+
+/// A `.clink` file that was read, and what was in it.
+pub struct SourceFile {
+    /// Its path, which for a file in an archive is the archive's path followed by its path inside.
+    pub path: String,
+    /// The file on disk it came from, which is the archive for a file in one.
+    pub on_disk: String,
+    pub content: String,
+}
+
+/// Splits `input` into tokens, each with where it starts. An error comes with where the token
+/// that caused it starts.
+pub fn tokenise(input: &str) -> Result<Vec<(Token, Span)>, (ParseError, Span)> {
     let mut tokens = Vec::new();
-    let mut rest = input.chars().peekable();
+    let mut rest = input.chars();
+    let mut span = Span::start();
+    let mut counted = 0;
     loop {
+        // count up to the start of the token, past any whitespace and comments before it
+        let start = input.len() - skip_blank(rest.as_str()).len();
+        span.advance(&input[counted..start]);
+        counted = start;
+
         let token;
-        (token, rest) = next_token(rest)?;
+        (token, rest) = next_token(rest).map_err(|e| (e, span))?;
         match token {
             None => break,
-            Some(token) => tokens.push(token),
+            Some(token) => tokens.push((token, span)),
         }
     }
     Ok(tokens)
 }
 
-fn next_token(i: Peekable<Chars>) -> Result<(Option<Token>, Peekable<Chars>), ParseError> {
-    let mut input = i;
-    while input.peek().map_or(false, |x| x.is_whitespace()) {
-        input.next();
+fn peek(input: &Chars) -> Option<char> {
+    input.clone().next()
+}
+
+/// Skips any whitespace and `//` comments, which run to the end of the line.
+fn skip_blank(mut input: &str) -> &str {
+    loop {
+        input = input.trim_start();
+        match input.strip_prefix("//") {
+            Some(comment) => input = comment.find('\n').map_or("", |end| &comment[end..]),
+            None => return input,
+        }
     }
-    match input.peek() {
+}
+
+fn next_token(i: Chars) -> Result<(Option<Token>, Chars), ParseError> {
+    let mut input = skip_blank(i.as_str()).chars();
+    match peek(&input) {
         None => Ok((None, input)),
         Some(char) => match char {
             '!' => {
@@ -125,6 +431,14 @@ fn next_token(i: Peekable<Chars>) -> Result<(Option<Token>, Peekable<Chars>), Pa
                 input.next();
                 Ok((Some(Token::Hash), input))
             }
+            '&' => {
+                input.next();
+                Ok((Some(Token::Ampersand), input))
+            }
+            '=' => {
+                input.next();
+                Ok((Some(Token::Equals), input))
+            }
             ';' => {
                 input.next();
                 Ok((Some(Token::Semicolon), input))
@@ -137,14 +451,35 @@ fn next_token(i: Peekable<Chars>) -> Result<(Option<Token>, Peekable<Chars>), Pa
                 input.next();
                 Ok((Some(Token::RBracket), input))
             }
+            '%' => {
+                input.next();
+                let mut directive = String::new();
+                while let Some(c) = peek(&input).filter(|&c| c != '\n') {
+                    input.next();
+                    directive.push(c);
+                }
+                Ok((
+                    Some(Token::Directive(directive.trim_end().to_string())),
+                    input,
+                ))
+            }
+            '^' => {
+                input.next();
+                match next_token(input)? {
+                    (Some(Token::Id(id)), input) => Ok((Some(Token::Tail(id)), input)),
+                    _ => Err(ParseError::ExpectedFunctionAfterCaret),
+                }
+            }
             _ => {
                 let mut id = String::new();
                 loop {
-                    match input.peek() {
+                    match peek(&input) {
                         None => break,
                         Some(char) => {
                             match char {
-                                '!' | '?' | ':' | '@' | '#' | ';' | '(' | ')' => break,
+                                '!' | '?' | ':' | '@' | '#' | '&' | '=' | ';' | '(' | ')' | '^'
+                                | '%' => break,
+                                '/' if input.as_str().starts_with("//") => break,
                                 _ => {}
                             }
                             if !char.is_whitespace() {
@@ -166,145 +501,706 @@ fn next_token(i: Peekable<Chars>) -> Result<(Option<Token>, Peekable<Chars>), Pa
 
 // -------------------------------------------------
 
-pub fn parse(main_func: &mut Vec<String>) -> Result<HashMap<Vec<String>, Vec<AST>>, ParseError> {
-    let directory = current_dir().map_err(|_| ParseError::CannotFindCurrentDir)?;
-    let mut functions = HashMap::new();
-    let mut packages = HashSet::new();
-    let mut imported_packages = HashSet::new();
-    let mut imports = HashMap::new();
+/// Everything discovered by scanning the source roots, before any references are resolved.
+#[derive(Default)]
+pub struct Project {
+    /// Unresolved function bodies, keyed by fully-qualified name, with where each token is in
+    /// the function's source.
+    pub functions: HashMap<Vec<String>, Vec<(Token, Span)>>,
+    /// The file each function was defined in.
+    pub sources: HashMap<Vec<String>, String>,
+    /// Every file and directory name seen, as a package path.
+    pub packages: HashSet<Vec<String>>,
+    /// The packages backed by a `.clink` file.
+    pub files: HashSet<Vec<String>>,
+    /// Every `.clink` file read.
+    pub files_read: Vec<SourceFile>,
+    pub imported_packages: HashSet<Vec<String>>,
+    pub imports: HashMap<Vec<String>, HashSet<Vec<String>>>,
+    /// The aliases each file declares, as the package each one stands for by its name.
+    pub aliases: HashMap<Vec<String>, HashMap<String, Vec<String>>>,
+    /// The file each package with aliases was read from.
+    pub alias_sources: HashMap<Vec<String>, String>,
+    pub warnings: Vec<ParseWarning>,
+    /// Every `%test` found, in the order the files were read.
+    pub tests: Vec<Test>,
+}
+
+/// An expectation written in a source file as `%test name "input" "output"`: running the function
+/// `name` from that file with the given input should print exactly the given output.
+pub struct Test {
+    pub function: Vec<String>,
+    pub input: Vec<u8>,
+    pub output: Vec<u8>,
+    /// The file the test was written in.
+    pub source: String,
+}
 
-    scan_dir(
-        &directory,
-        Vec::new(),
-        &mut functions,
-        &mut packages,
-        &mut imported_packages,
-        &mut imports,
-    )?;
+/// Globs picking which `.clink` files to read, matched against paths relative to their root, like
+/// `lib/**/*.clink`. `*` and `?` match within one part of a path, and `**` across any number.
+#[derive(Default, Clone)]
+pub struct FileFilter {
+    /// If not empty, only files matching one of these are read.
+    pub only: Vec<String>,
+    /// Files matching any of these are skipped.
+    pub exclude: Vec<String>,
+}
+
+impl FileFilter {
+    fn allows(&self, path: &str) -> bool {
+        let path: Vec<char> = path.chars().collect();
+        let matches = |glob: &String| glob_match(&glob.chars().collect::<Vec<_>>(), &path);
+        (self.only.is_empty() || self.only.iter().any(matches)) && !self.exclude.iter().any(matches)
+    }
+}
 
-    for pkg in imported_packages {
-        if !packages.contains(&pkg) {
-            return Err(ParseError::UnknownPackage(pkg))
+fn glob_match(glob: &[char], path: &[char]) -> bool {
+    match glob {
+        [] => path.is_empty(),
+        ['*', '*', '/', rest @ ..] => {
+            // any number of whole directories, including none
+            glob_match(rest, path)
+                || (0..path.len()).any(|i| path[i] == '/' && glob_match(rest, &path[i + 1..]))
         }
+        ['*', '*', rest @ ..] => (0..=path.len()).any(|i| glob_match(rest, &path[i..])),
+        ['*', rest @ ..] => (0..=path.len())
+            .take_while(|&i| i == 0 || path[i - 1] != '/')
+            .any(|i| glob_match(rest, &path[i..])),
+        ['?', rest @ ..] => !path.is_empty() && path[0] != '/' && glob_match(rest, &path[1..]),
+        [c, rest @ ..] => path.first() == Some(c) && glob_match(rest, &path[1..]),
     }
+}
+
+/// Reads and tokenises every file under the roots that `filter` allows, without resolving any
+/// references. This is much cheaper than `parse` on a large project, and is enough to know which
+/// packages and functions exist.
+pub fn scan(roots: &[PathBuf], filter: &FileFilter) -> Result<Project, ParseError> {
+    let mut project = Project::default();
+
+    // every root is merged into the same package namespace
+    let roots = if roots.is_empty() {
+        vec![current_dir().map_err(|_| ParseError::CannotFindCurrentDir)?]
+    } else {
+        roots.to_vec()
+    };
+
+    for root in &roots {
+        let (dir, archive) = if is_archive(root) {
+            (
+                read_archive(root, filter)?,
+                Some(root.display().to_string()),
+            )
+        } else {
+            (read_dir(root, &[], filter)?, None)
+        };
+        scan_dir(&dir, Vec::new(), archive.as_deref(), &mut project)?;
+    }
+
+    let packages: Vec<_> = project.packages.iter().cloned().collect();
+    let functions: Vec<_> = project.functions.keys().cloned().collect();
+    check_case(packages, &mut project.warnings);
+    check_case(functions, &mut project.warnings);
+
+    Ok(project)
+}
+
+pub fn parse(
+    roots: &[PathBuf],
+    filter: &FileFilter,
+    main_func: &mut Vec<String>,
+    report: &mut ParseReport,
+) -> Result<HashMap<Vec<String>, Vec<AST>>, ParseError> {
+    parse_entries(roots, filter, &[main_func.clone()], report)
+}
+
+/// Parses every function reachable from any of the given entries into a single program.
+pub fn parse_entries(
+    roots: &[PathBuf],
+    filter: &FileFilter,
+    entries: &[Vec<String>],
+    report: &mut ParseReport,
+) -> Result<HashMap<Vec<String>, Vec<AST>>, ParseError> {
+    parse_project(scan(roots, filter)?, entries, report)
+}
+
+/// Like `parse_entries`, on a project that's already been scanned.
+pub fn parse_project(
+    mut project: Project,
+    entries: &[Vec<String>],
+    report: &mut ParseReport,
+) -> Result<HashMap<Vec<String>, Vec<AST>>, ParseError> {
+    report.warnings.append(&mut project.warnings);
+    report.files_read.append(&mut project.files_read);
+    check_packages(&project)?;
 
     let mut func_defs = HashMap::new();
 
-    parse_funcs(main_func, &mut func_defs, &mut functions, &mut imports)?;
+    for entry in entries {
+        parse_funcs(
+            entry,
+            &mut func_defs,
+            &mut project.functions,
+            &project.sources,
+            &mut project.imports,
+            &project.aliases,
+            &mut report.resolutions,
+        )?;
+    }
 
     Ok(func_defs)
 }
 
-fn scan_dir(
-    dir: &Path,
-    pkg: Vec<String>,
-    functions: &mut HashMap<Vec<String>, Vec<Token>>,
-    packages: &mut HashSet<Vec<String>>,
-    imported_packages: &mut HashSet<Vec<String>>,
-    imports: &mut HashMap<Vec<String>, HashSet<Vec<String>>>,
-) -> Result<(), ParseError> {
+/// A function that failed to resolve when checking a whole project.
+pub struct FunctionError {
+    pub function: Vec<String>,
+    /// The file the function was defined in.
+    pub source: String,
+    pub error: ParseError,
+}
+
+/// Resolves every function in the project, not just those reachable from an entry, collecting an
+/// error for each one that fails rather than stopping at the first. Errors in the project as a
+/// whole, like importing a package that doesn't exist, still stop it straight away.
+pub fn check_all(
+    mut project: Project,
+    report: &mut ParseReport,
+) -> Result<Vec<FunctionError>, ParseError> {
+    report.warnings.append(&mut project.warnings);
+    report.files_read.append(&mut project.files_read);
+    check_packages(&project)?;
+
+    let mut names: Vec<Vec<String>> = project.functions.keys().cloned().collect();
+    names.sort();
+    let mut func_defs = HashMap::new();
+    let mut errors = Vec::new();
+    for name in &names {
+        if func_defs.contains_key(name) {
+            continue;
+        }
+        let result = parse_funcs(
+            name,
+            &mut func_defs,
+            &mut project.functions,
+            &project.sources,
+            &mut project.imports,
+            &project.aliases,
+            &mut report.resolutions,
+        );
+        if let Err(error) = result {
+            // everything `parse_funcs` got through is parsed or still waiting, except the
+            // function it failed on
+            let failed = names
+                .iter()
+                .find(|f| !project.functions.contains_key(*f) && !func_defs.contains_key(*f))
+                .unwrap()
+                .clone();
+            // stand in for it, so references to it from other functions still resolve
+            func_defs.insert(failed.clone(), Vec::new());
+            errors.push(FunctionError {
+                source: project.sources[&failed].clone(),
+                function: failed,
+                error,
+            });
+        }
+    }
+    Ok(errors)
+}
+
+/// Checks that every imported package, and every package an alias stands for, exists.
+fn check_packages(project: &Project) -> Result<(), ParseError> {
+    for pkg in &project.imported_packages {
+        if !project.packages.contains(pkg) {
+            return Err(ParseError::UnknownPackage(pkg.clone()));
+        }
+    }
+
+    for (file, aliases) in &project.aliases {
+        for (alias, target) in aliases {
+            if project.packages.contains(&vec![alias.clone()]) {
+                let source = project.alias_sources[file].clone();
+                return Err(ParseError::AliasShadowsPackage(alias.clone(), source));
+            }
+            if !project.packages.contains(target) {
+                return Err(ParseError::UnknownPackage(target.clone()));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Loads a program from JSON, as an object mapping dotted function names to their `AST`s.
+pub fn program_from_json(json: &str) -> Result<HashMap<Vec<String>, Vec<AST>>, ParseError> {
+    let functions: HashMap<String, Vec<AST>> =
+        serde_json::from_str(json).map_err(|e| ParseError::InvalidAst(e.to_string()))?;
+    Ok(functions
+        .into_iter()
+        .map(|(name, asts)| (name.split(".").map(|x| x.to_string()).collect(), asts))
+        .collect())
+}
+
+/// Writes a program as JSON in the form `program_from_json` reads.
+pub fn program_to_json(program: &HashMap<Vec<String>, Vec<AST>>) -> String {
+    let functions: HashMap<String, &Vec<AST>> = program
+        .iter()
+        .map(|(name, asts)| (name.join("."), asts))
+        .collect();
+    serde_json::to_string(&functions).unwrap()
+}
+
+/// Warns about names with the same parent whose last parts differ only by case.
+fn check_case(mut names: Vec<Vec<String>>, warnings: &mut Vec<ParseWarning>) {
+    names.sort();
+    let mut seen: HashMap<Vec<String>, Vec<String>> = HashMap::new();
+    for name in names {
+        let mut folded = name.clone();
+        if let Some(last) = folded.last_mut() {
+            *last = last.to_lowercase();
+        }
+        match seen.get(&folded) {
+            Some(other) => warnings.push(ParseWarning::CaseCollision(other.clone(), name)),
+            None => {
+                seen.insert(folded, name);
+            }
+        }
+    }
+}
+
+/// Whether a root is a gzipped tarball of a project rather than a directory.
+pub fn is_archive(path: &Path) -> bool {
+    let name = path.to_string_lossy();
+    name.ends_with(".tar.gz") || name.ends_with(".tgz")
+}
+
+/// A directory of sources. Directories on disk and in archives are both read into one of these up
+/// front, so they can be scanned the same way.
+struct SourceDir {
+    /// The directory's path, for messages.
+    path: String,
+    /// Everything in the directory, by file name.
+    entries: BTreeMap<String, SourceEntry>,
+}
+
+enum SourceEntry {
+    Dir(SourceDir),
+    /// A file's path, and its contents if it's a `.clink` file.
+    File(String, Option<String>),
+}
+
+impl SourceDir {
+    fn new(path: String) -> SourceDir {
+        SourceDir {
+            path,
+            entries: BTreeMap::new(),
+        }
+    }
+
+    /// The directory `name` inside this one, which is added if it isn't there yet.
+    fn subdir(&mut self, name: &str) -> &mut SourceDir {
+        let path = format!("{}/{}", self.path, name);
+        let entry = self
+            .entries
+            .entry(name.to_string())
+            .or_insert_with(|| SourceEntry::Dir(SourceDir::new(path.clone())));
+        if let SourceEntry::File(..) = entry {
+            // only a broken archive has a file and a directory with the same path
+            *entry = SourceEntry::Dir(SourceDir::new(path));
+        }
+        match entry {
+            SourceEntry::Dir(dir) => dir,
+            SourceEntry::File(..) => unreachable!(),
+        }
+    }
+}
+
+fn is_clink(name: &str) -> bool {
+    Path::new(name).extension().map_or(false, |t| t == "clink")
+}
+
+/// Reads the directory `dir`, which is at `rel` inside its root, leaving out files `filter`
+/// doesn't allow.
+fn read_dir(dir: &Path, rel: &[String], filter: &FileFilter) -> Result<SourceDir, ParseError> {
+    let mut source_dir = SourceDir::new(dir.display().to_string());
     for file in dir
         .read_dir()
-        .map_err(|_| ParseError::ErrorReadingDirectory)?
+        .map_err(|_| ParseError::ErrorReadingDirectory(dir.to_string_lossy().to_string()))?
     {
         if let Ok(file) = file {
-            let mut file_name = pkg.clone();
-            file_name.push(
-                file.path()
-                    .with_extension("")
-                    .file_name()
-                    .ok_or(ParseError::CannotGetMetadata)?
-                    .to_str()
-                    .ok_or(ParseError::OSStringConversionError)?
-                    .to_string(),
-            );
-            packages.insert(file_name.clone());
-            if file.metadata().unwrap().is_dir() {
-                scan_dir(
-                    file.path().as_path(),
-                    file_name,
-                    functions,
-                    packages,
-                    imported_packages,
-                    imports,
-                )?;
-            } else if let Some(t) = file.path().extension() {
-                //check if clink file
-                if t == "clink" {
-                    let content = fs::read_to_string(file.path()).map_err(|_| {
-                        match file.path().to_str() {
-                            Some(th) => ParseError::FileNotFound(th.to_string()),
-                            None => ParseError::OSStringConversionError,
-                        }
-                    })?;
-
-                    let tokenised = tokenise(content.as_str())?;
-
-                    let mut defining = false;
-                    let mut importing = false;
-                    let mut current_func = Vec::new();
-                    let mut current_func_name = String::new();
-
-                    for token in tokenised {
-                        if importing {
-                            if let Token::Id(id) = token {
-                                if let None = imports.get(&file_name) {
-                                    imports.insert(file_name.clone(), HashSet::new());
-                                }
-                                imported_packages.insert(id.clone());
-                                imports.get_mut(&file_name).unwrap().insert(id);
-                            } else {
-                                return Err(ParseError::ExpectedPackageName);
-                            }
-                            importing = false;
-                        } else if defining {
-                            if let Token::Semicolon = token {
-                                let mut f_n = file_name.clone();
-                                f_n.push(current_func_name);
-                                if functions.contains_key(&f_n) {
-                                    return Err(ParseError::FunctionDefinedTwice(f_n.join(".")));
-                                }
-                                functions.insert(f_n, current_func);
-                                current_func = Vec::new();
-                                current_func_name = String::new();
-                                defining = false;
-                            } else {
-                                current_func.push(token);
-                            }
-                        } else {
-                            if let Token::Bang = token {
-                                importing = true;
-                            } else if let Token::Id(id) = token {
-                                if id.len() != 1 {
-                                    return Err(ParseError::CannotDefineFunctionOutsidePackage(id));
-                                }
-                                current_func_name = id.first().unwrap().clone();
-                                defining = true;
-                            }
-                        }
-                    }
+            let source = file.path().display().to_string();
+            let name = file
+                .file_name()
+                .to_str()
+                .ok_or(ParseError::OSStringConversionError(source.clone()))?
+                .to_string();
+            let mut file_rel = rel.to_vec();
+            file_rel.push(name.clone());
 
-                    if defining {
-                        let mut f_n = file_name.clone();
-                        f_n.push(current_func_name);
-                        if functions.contains_key(&f_n) {
-                            return Err(ParseError::FunctionDefinedTwice(f_n.join(".")));
-                        }
-                        functions.insert(f_n, current_func);
-                    }
+            let is_dir = file
+                .metadata()
+                .map_err(|_| ParseError::CannotGetMetadata)?
+                .is_dir();
+            let entry = if is_dir {
+                SourceEntry::Dir(read_dir(&file.path(), &file_rel, filter)?)
+            } else if !filter.allows(&file_rel.join("/")) {
+                continue;
+            } else if is_clink(&name) {
+                let content = fs::read_to_string(file.path()).map_err(|e| {
+                    // `dir` is `rel` deep inside its root
+                    let root = dir.ancestors().nth(rel.len()).unwrap_or(dir);
+                    ParseError::FileNotFound(
+                        source.clone(),
+                        format!("while scanning root `{}`", root.display()),
+                        e.to_string(),
+                    )
+                })?;
+                SourceEntry::File(source, Some(content))
+            } else {
+                SourceEntry::File(source, None)
+            };
+            source_dir.entries.insert(name, entry);
+        }
+    }
+    Ok(source_dir)
+}
+
+/// Reads a gzipped tarball into memory, as though it had been unpacked into a directory.
+fn read_archive(archive: &Path, filter: &FileFilter) -> Result<SourceDir, ParseError> {
+    let name = archive.display().to_string();
+    let error = |e: std::io::Error| ParseError::ErrorReadingArchive(name.clone(), e.to_string());
+
+    let mut root = SourceDir::new(name.clone());
+    let file = fs::File::open(archive).map_err(error)?;
+    let mut tar = tar::Archive::new(GzDecoder::new(file));
+    for entry in tar.entries().map_err(error)? {
+        let mut entry = entry.map_err(error)?;
+        let parts: Vec<String> = entry
+            .path()
+            .map_err(error)?
+            .components()
+            .filter_map(|c| match c {
+                Component::Normal(part) => Some(part.to_string_lossy().to_string()),
+                _ => None,
+            })
+            .collect();
+        let (file_name, dirs) = match parts.split_last() {
+            Some(split) => split,
+            None => continue,
+        };
+
+        // archives needn't list a directory before what's in it
+        let mut dir = &mut root;
+        for part in dirs {
+            dir = dir.subdir(part);
+        }
+        let entry_type = entry.header().entry_type();
+        if entry_type.is_dir() {
+            dir.subdir(file_name);
+            continue;
+        }
+        if !entry_type.is_file() || !filter.allows(&parts.join("/")) {
+            continue;
+        }
+
+        let source = format!("{}/{}", name, parts.join("/"));
+        let content = if is_clink(file_name) {
+            let mut content = String::new();
+            entry.read_to_string(&mut content).map_err(error)?;
+            Some(content)
+        } else {
+            None
+        };
+        dir.entries
+            .insert(file_name.clone(), SourceEntry::File(source, content));
+    }
+    Ok(root)
+}
+
+/// Scans the directory `dir`, which is the package `pkg`, and is inside `archive` if it was read
+/// from one.
+fn scan_dir(
+    dir: &SourceDir,
+    pkg: Vec<String>,
+    archive: Option<&str>,
+    project: &mut Project,
+) -> Result<(), ParseError> {
+    // the path of every `.clink` file and directory here, by the package it is
+    let mut package_paths: HashMap<Vec<String>, String> = HashMap::new();
+
+    for (name, entry) in &dir.entries {
+        let mut file_name = pkg.clone();
+        file_name.push(
+            Path::new(name)
+                .with_extension("")
+                .to_string_lossy()
+                .to_string(),
+        );
+        project.packages.insert(file_name.clone());
+
+        let path = match entry {
+            SourceEntry::Dir(dir) => Some(&dir.path),
+            SourceEntry::File(path, Some(_)) => Some(path),
+            SourceEntry::File(_, None) => None,
+        };
+        if let Some(path) = path {
+            if let Some(other) = package_paths.insert(file_name.clone(), path.clone()) {
+                return Err(ParseError::PackagePathConflict(other, path.clone()));
+            }
+        }
+
+        match entry {
+            SourceEntry::Dir(dir) => scan_dir(dir, file_name, archive, project)?,
+            SourceEntry::File(source, Some(content)) => {
+                project.files_read.push(SourceFile {
+                    path: source.clone(),
+                    on_disk: archive.unwrap_or(source).to_string(),
+                    content: content.clone(),
+                });
+                scan_file(content, file_name, source, project)?
+            }
+            SourceEntry::File(_, None) => {}
+        }
+    }
+
+    Ok(())
+}
+
+/// Reads the imports, aliases, definitions and tests in a `.clink` file.
+fn scan_file(
+    content: &str,
+    file_name: Vec<String>,
+    source: &String,
+    project: &mut Project,
+) -> Result<(), ParseError> {
+    let tokenised = tokenise(content).map_err(|(e, span)| e.at(source, span))?;
+    let source = source.clone();
+    project.files.insert(file_name.clone());
+
+    let mut defining = false;
+    let mut importing = false;
+    let mut continuing = false;
+    let mut aliasing = false;
+    let mut current_func = Vec::new();
+    let mut current_func_name = String::new();
+    let mut current_func_span = Span::start();
+    let mut file_imports = HashSet::new();
+
+    for (token, span) in tokenised {
+        if importing {
+            if let Token::Id(id) = token {
+                if let None = project.imports.get(&file_name) {
+                    project.imports.insert(file_name.clone(), HashSet::new());
+                }
+                if !file_imports.insert(id.clone()) {
+                    project
+                        .warnings
+                        .push(ParseWarning::DuplicateImport(id.clone(), source.clone()));
+                }
+                project.imported_packages.insert(id.clone());
+                project.imports.get_mut(&file_name).unwrap().insert(id);
+            } else {
+                let e = ParseError::ExpectedPackageName('!', Some(token), source.clone());
+                return Err(e.at(&source, span));
+            }
+            importing = false;
+        } else if aliasing {
+            if let Token::Id(target) = token {
+                let aliases = project.aliases.entry(file_name.clone()).or_default();
+                let alias = std::mem::take(&mut current_func_name);
+                if aliases.insert(alias.clone(), target).is_some() {
+                    let e = ParseError::AliasDefinedTwice(alias, source.clone());
+                    return Err(e.at(&source, current_func_span));
                 }
+                project
+                    .alias_sources
+                    .insert(file_name.clone(), source.clone());
+            } else {
+                let e = ParseError::ExpectedPackageName('=', Some(token), source.clone());
+                return Err(e.at(&source, span));
+            }
+            aliasing = false;
+        } else if defining {
+            if let Token::Equals = token {
+                // `name = package` declares an alias rather than a function
+                if !current_func.is_empty() || continuing {
+                    let e = ParseError::UnexpectedToken(token, source.clone());
+                    return Err(e.at(&source, span));
+                }
+                defining = false;
+                aliasing = true;
+            } else if let Token::Semicolon = token {
+                let mut f_n = file_name.clone();
+                f_n.push(current_func_name);
+                if continuing {
+                    continue_function(f_n, current_func, &source, project)
+                } else {
+                    define_function(f_n, current_func, &source, project)
+                }
+                .map_err(|e| e.at(&source, current_func_span))?;
+                current_func = Vec::new();
+                current_func_name = String::new();
+                defining = false;
+                continuing = false;
+            } else if let Token::Ampersand | Token::Directive(_) = token {
+                let e = ParseError::UnexpectedToken(token, source.clone());
+                return Err(e.at(&source, span));
+            } else {
+                current_func.push((token, span));
             }
+        } else if continuing {
+            // `&name` appends to an earlier definition of `name` in this file
+            if let Token::Id(id) = token {
+                if id.len() != 1 {
+                    let e = ParseError::CannotDefineFunctionOutsidePackage(id);
+                    return Err(e.at(&source, span));
+                }
+                current_func_name = id.first().unwrap().clone();
+                current_func_span = span;
+                defining = true;
+            } else {
+                let e = ParseError::UnexpectedToken(token, source.clone());
+                return Err(e.at(&source, span));
+            }
+        } else {
+            if let Token::Bang = token {
+                importing = true;
+            } else if let Token::Ampersand = token {
+                continuing = true;
+            } else if let Token::Id(id) = token {
+                if id.len() != 1 {
+                    let e = ParseError::CannotDefineFunctionOutsidePackage(id);
+                    return Err(e.at(&source, span));
+                }
+                current_func_name = id.first().unwrap().clone();
+                current_func_span = span;
+                defining = true;
+            } else if let Token::Semicolon = token {
+                // stray separators between definitions are harmless
+            } else if let Token::Directive(directive) = token {
+                let (name, input, output) = parse_test(&directive).ok_or_else(|| {
+                    ParseError::InvalidTest(directive.clone(), source.clone()).at(&source, span)
+                })?;
+                let mut function = file_name.clone();
+                function.push(name);
+                project.tests.push(Test {
+                    function,
+                    input,
+                    output,
+                    source: source.clone(),
+                });
+            } else {
+                let e = ParseError::UnexpectedToken(token, source.clone());
+                return Err(e.at(&source, span));
+            }
+        }
+    }
+
+    let mut end = Span::start();
+    end.advance(content);
+    if importing {
+        let e = ParseError::ExpectedPackageName('!', None, source.clone());
+        return Err(e.at(&source, end));
+    }
+    if aliasing {
+        let e = ParseError::ExpectedPackageName('=', None, source.clone());
+        return Err(e.at(&source, end));
+    }
+
+    if defining {
+        let mut f_n = file_name.clone();
+        f_n.push(current_func_name);
+        if continuing {
+            continue_function(f_n, current_func, &source, project)
+        } else {
+            define_function(f_n, current_func, &source, project)
+        }
+        .map_err(|e| e.at(&source, current_func_span))?;
+    }
+
+    Ok(())
+}
+
+/// Reads a directive of the form `test name "input" "output"`.
+fn parse_test(directive: &str) -> Option<(String, Vec<u8>, Vec<u8>)> {
+    let rest = directive.strip_prefix("test")?.trim_start();
+    let (name, rest) = rest.split_once(char::is_whitespace)?;
+    if name.contains('.') {
+        return None;
+    }
+    let (input, rest) = parse_quoted(rest.trim_start())?;
+    let (output, rest) = parse_quoted(rest.trim_start())?;
+    if !rest.trim().is_empty() {
+        return None;
+    }
+    Some((name.to_string(), input, output))
+}
+
+/// Reads a string in double quotes, which can use the escapes `\n`, `\t`, `\\`, `\"` and `\xNN`,
+/// returning its bytes and whatever follows it.
+fn parse_quoted(s: &str) -> Option<(Vec<u8>, &str)> {
+    let mut chars = s.strip_prefix('"')?.char_indices();
+    let mut bytes = Vec::new();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            // `i` doesn't count the opening quote
+            '"' => return Some((bytes, &s[i + 2..])),
+            '\\' => match chars.next()?.1 {
+                'n' => bytes.push(b'\n'),
+                't' => bytes.push(b'\t'),
+                '\\' => bytes.push(b'\\'),
+                '"' => bytes.push(b'"'),
+                'x' => {
+                    let hex: String = [chars.next()?.1, chars.next()?.1].iter().collect();
+                    bytes.push(u8::from_str_radix(&hex, 16).ok()?);
+                }
+                _ => return None,
+            },
+            c => bytes.extend(c.to_string().as_bytes()),
         }
     }
+    None
+}
+
+fn define_function(
+    name: Vec<String>,
+    body: Vec<(Token, Span)>,
+    source: &String,
+    project: &mut Project,
+) -> Result<(), ParseError> {
+    if let Some(first) = project.sources.get(&name) {
+        return Err(ParseError::FunctionDefinedTwice(
+            name.join("."),
+            first.clone(),
+            source.clone(),
+        ));
+    }
+    project.sources.insert(name.clone(), source.clone());
+    project.functions.insert(name, body);
+    Ok(())
+}
 
+fn continue_function(
+    name: Vec<String>,
+    mut body: Vec<(Token, Span)>,
+    source: &String,
+    project: &mut Project,
+) -> Result<(), ParseError> {
+    if project.sources.get(&name) != Some(source) {
+        return Err(ParseError::ContinuesUndefinedFunction(
+            name.join("."),
+            source.clone(),
+        ));
+    }
+    project.functions.get_mut(&name).unwrap().append(&mut body);
     Ok(())
 }
 
 fn parse_funcs(
     current: &Vec<String>,
     func_defs: &mut HashMap<Vec<String>, Vec<AST>>,
-    functions: &mut HashMap<Vec<String>, Vec<Token>>,
+    functions: &mut HashMap<Vec<String>, Vec<(Token, Span)>>,
+    sources: &HashMap<Vec<String>, String>,
     imports: &mut HashMap<Vec<String>, HashSet<Vec<String>>>,
+    aliases: &HashMap<Vec<String>, HashMap<String, Vec<String>>>,
+    resolutions: &mut Vec<Resolution>,
 ) -> Result<(), ParseError> {
     let mut dirn = current.clone();
     dirn.pop();
@@ -318,69 +1214,221 @@ fn parse_funcs(
     let mut to_parse = Vec::new();
 
     let mut new_f = Vec::new();
+    let source = &sources[current];
+    // whether each bracket open so far has had a `:`, to find one too many
+    let mut colons = vec![false];
+    let mut extra_colon = None;
+    // where each bracket still open was opened, and the first `)` with none open
+    let mut brackets = Vec::new();
+    let mut extra_bracket = None;
+    // each tail call as written and where, in order
+    let mut tails = Vec::new();
 
-    for token in f {
-        if let Token::Id(id) = token {
-            let mut found = None;
-            if current == &id || functions.contains_key(&id) || func_defs.contains_key(&id) {
-                found = Some(id.clone());
-            } else {
-                let mut ds = Vec::new();
-                for d in &dirn {
-                    ds.push(d.clone());
-                    let mut m = ds.clone();
-                    m.append(&mut id.clone());
-                    if current == &m || functions.contains_key(&m) || func_defs.contains_key(&m) {
-                        if let None = found {
-                            found = Some(m.clone());
-                        } else {
-                            return Err(ParseError::AmbiguousReference(id));
-                        }
+    for (token, span) in f {
+        let (id, tail) = match token {
+            Token::Id(id) => (id, false),
+            Token::Tail(id) => (id, true),
+            token => {
+                match token {
+                    Token::LBracket => {
+                        colons.push(false);
+                        brackets.push(span);
                     }
-                }
-            }
-
-            if let None = found {
-                for import in imports.get(&dirn).unwrap() {
-                    let mut ds = Vec::new();
-                    for d in import {
-                        ds.push(d.clone());
-                        let mut m = ds.clone();
-                        m.append(&mut id.clone());
-                        if current == &m || functions.contains_key(&m) || func_defs.contains_key(&m)
-                        {
-                            if let None = found {
-                                found = Some(m.clone());
-                            } else {
-                                return Err(ParseError::AmbiguousReference(id));
-                            }
+                    Token::RBracket => match brackets.pop() {
+                        Some(_) => {
+                            colons.pop();
+                        }
+                        None => {
+                            extra_bracket.get_or_insert(span);
                         }
+                    },
+                    Token::Colon => {
+                        if *colons.last().unwrap() && extra_colon.is_none() {
+                            extra_colon = Some(span);
+                        }
+                        *colons.last_mut().unwrap() = true;
                     }
+                    _ => {}
                 }
+                new_f.push(token);
+                continue;
             }
+        };
+        let exists = |f: &Vec<String>| {
+            current == f || functions.contains_key(f) || func_defs.contains_key(f)
+        };
+        // an alias stands for the whole package path it was declared with
+        let full_id = match aliases.get(&dirn).and_then(|a| a.get(&id[0])) {
+            Some(target) if id.len() > 1 => [target.as_slice(), &id[1..]].concat(),
+            _ => id.clone(),
+        };
+        let mut tried = Vec::new();
+        let found = resolve(&full_id, &dirn, imports.get(&dirn), exists, &mut tried)
+            .map_err(|e| e.at(source, span))?;
 
-            match found {
-                Some(x) => {
-                    to_parse.push(x.clone());
-                    new_f.push(Token::Id(x))
+        match found {
+            Some(x) => {
+                resolutions.push(Resolution {
+                    function: current.clone(),
+                    reference: id,
+                    target: x.clone(),
+                });
+                to_parse.push(x.clone());
+                if tail {
+                    tails.push((resolutions.last().unwrap().reference.clone(), span));
+                    new_f.push(Token::Tail(x));
+                } else {
+                    new_f.push(Token::Id(x));
                 }
-                None => return Err(ParseError::UnknownFunction(id.clone())),
             }
-        } else {
-            new_f.push(token);
+            None => {
+                let candidates = packages_defining(&id, functions, func_defs);
+                let e = ParseError::UnknownFunction(id.clone(), candidates, tried);
+                return Err(e.at(source, span));
+            }
         }
     }
 
-    let p_f = parse_functions(parse_colon(parse_brackets(new_f)?)?);
-    func_defs.insert(current.clone(), p_f);
+    let bracketed = parse_brackets(new_f).map_err(|e| {
+        // a stray `)` stops it before it gets to the end, where unclosed ones are found
+        match extra_bracket.or(brackets.last().copied()) {
+            Some(span) => e.at(source, span),
+            None => e,
+        }
+    })?;
+    let split = parse_colon(bracketed).map_err(|e| match extra_colon {
+        Some(span) => e.at(source, span),
+        None => e,
+    })?;
+    let asts = parse_functions(split);
+    let mut last = Vec::new();
+    tail_calls_last(&asts, true, &mut last);
+    if let Some(i) = last.iter().position(|last| !last) {
+        let (id, span) = tails.swap_remove(i);
+        return Err(ParseError::TailCallNotLast(id).at(source, span));
+    }
+    func_defs.insert(current.clone(), asts);
 
     for mut t_p in to_parse {
-        parse_funcs(&mut t_p, func_defs, functions, imports)?;
+        parse_funcs(
+            &mut t_p,
+            func_defs,
+            functions,
+            sources,
+            imports,
+            aliases,
+            resolutions,
+        )?;
     }
 
     Ok(())
 }
 
+/// Adds whether each tail call in `asts` is the last thing run, in the order they were written,
+/// given whether anything runs after `asts` at all.
+fn tail_calls_last(asts: &[AST], tail: bool, last: &mut Vec<bool>) {
+    // `asts` are in the order they run, which is backwards from how they were written
+    for (i, ast) in asts.iter().enumerate().rev() {
+        let tail = tail && i + 1 == asts.len();
+        match ast {
+            AST::TailCall(_) => last.push(tail),
+            AST::Split(l, r) => {
+                tail_calls_last(l, tail, last);
+                tail_calls_last(r, tail, last);
+            }
+            AST::Bracketed(asts) => tail_calls_last(asts, tail, last),
+            _ => {}
+        }
+    }
+}
+
+/// Resolves a reference `id` made in package `dirn` to a fully-qualified function name. Candidates
+/// come in tiers, and the first tier with any that `exists` wins:
+///
+/// 1. `id` inside `dirn`, the package of the file making the reference
+/// 2. `id` inside each package enclosing `dirn` in turn, innermost first
+/// 3. `id` itself, as a fully-qualified name from the top of the project
+/// 4. `id` inside any of the file's `imports`, or any package enclosing them
+///
+/// So a function in the same package beats one in an enclosing package, which beats one named
+/// from the top, which beats an imported one. More than one distinct match within a tier, which
+/// can only happen among imports, is ambiguous. Every candidate looked at is added to `tried`, in
+/// order.
+fn resolve(
+    id: &Vec<String>,
+    dirn: &Vec<String>,
+    imports: Option<&HashSet<Vec<String>>>,
+    exists: impl Fn(&Vec<String>) -> bool,
+    tried: &mut Vec<Vec<String>>,
+) -> Result<Option<Vec<String>>, ParseError> {
+    // every prefix of `pkg` with `id` appended
+    let within = |pkg: &Vec<String>| -> Vec<Vec<String>> {
+        (1..=pkg.len())
+            .map(|n| pkg[..n].iter().chain(id).cloned().collect())
+            .collect()
+    };
+
+    let mut imported: Vec<Vec<String>> = imports.into_iter().flatten().flat_map(within).collect();
+    // iterating a set isn't ordered, so keep the error stable
+    imported.sort();
+    let mut tiers: Vec<Vec<Vec<String>>> =
+        within(dirn).into_iter().rev().map(|f| vec![f]).collect();
+    tiers.push(vec![id.clone()]);
+    tiers.push(imported);
+
+    for tier in tiers {
+        for f in &tier {
+            if !tried.contains(f) {
+                tried.push(f.clone());
+            }
+        }
+        let mut found: Vec<Vec<String>> = tier.into_iter().filter(|f| exists(f)).collect();
+        found.dedup();
+        match found.len() {
+            0 => continue,
+            1 => return Ok(found.pop()),
+            _ => return Err(ParseError::AmbiguousReference(id.clone(), found)),
+        }
+    }
+    Ok(None)
+}
+
+/// Finds the packages that define a function called `id`, for suggesting a missing import.
+fn packages_defining(
+    id: &Vec<String>,
+    functions: &HashMap<Vec<String>, Vec<(Token, Span)>>,
+    func_defs: &HashMap<Vec<String>, Vec<AST>>,
+) -> Vec<Vec<String>> {
+    let mut candidates: Vec<Vec<String>> = functions
+        .keys()
+        .chain(func_defs.keys())
+        .filter(|f| f.len() > id.len() && f.ends_with(id))
+        .map(|f| f[..f.len() - id.len()].to_vec())
+        .collect();
+    candidates.sort();
+    candidates.dedup();
+    candidates
+}
+
+/// Parses a function body on its own, with no packages to resolve calls in, so it can't make any.
+pub fn parse_body(body: &str) -> Result<Vec<AST>, ParseError> {
+    let tokens: Vec<Token> = tokenise(body)
+        .map_err(|(e, _)| e)?
+        .into_iter()
+        .map(|(token, _)| token)
+        .collect();
+    for token in &tokens {
+        if let Token::Id(id) | Token::Tail(id) = token {
+            return Err(ParseError::UnknownFunction(
+                id.clone(),
+                Vec::new(),
+                Vec::new(),
+            ));
+        }
+    }
+    Ok(parse_functions(parse_colon(parse_brackets(tokens)?)?))
+}
+
 fn parse_brackets(func: Vec<Token>) -> Result<Vec<Token>, ParseError> {
     parse_brackets_each(0, &mut func.into_iter().peekable())
 }
@@ -397,6 +1445,9 @@ fn parse_brackets_each(
                 tokens.push(Token::Bracket(parse_brackets_each(level + 1, func)?));
             }
             Some(Token::RBracket) => {
+                if level == 0 {
+                    return Err(ParseError::UnbalancedParentheses(')'));
+                }
                 func.next();
                 return Ok(tokens);
             }
@@ -404,6 +1455,7 @@ fn parse_brackets_each(
                 let t = func.next();
                 tokens.push(t.unwrap())
             }
+            None if level > 0 => return Err(ParseError::UnbalancedParentheses('(')),
             None => return Ok(tokens),
         }
     }
@@ -464,9 +1516,361 @@ fn parse_functions(func: Vec<Token>) -> Vec<AST> {
             Token::Hash => current.push(AST::Print),
             Token::Split(l, r) => current.push(AST::Split(parse_functions(l), parse_functions(r))),
             Token::Id(id) => current.push(AST::Id(id)),
+            Token::Tail(id) => current.push(AST::TailCall(id)),
             _ => {}
         }
     }
 
     current
 }
+
+/// Splices the contents of every bracket into the sequence around it, dropping empty ones. This
+/// runs the same, since brackets only group code for `:` while parsing, and changes nothing if
+/// done twice.
+pub fn canonicalize(asts: Vec<AST>) -> Vec<AST> {
+    let mut flat = Vec::with_capacity(asts.len());
+    for ast in asts {
+        match ast {
+            AST::Bracketed(asts) => flat.extend(canonicalize(asts)),
+            AST::Split(l, r) => flat.push(AST::Split(canonicalize(l), canonicalize(r))),
+            ast => flat.push(ast),
+        }
+    }
+    flat
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Scans a project of the given files, each a path inside the root like `x/main.clink` and its
+    /// contents.
+    fn scan_files(files: &[(&str, &str)]) -> Result<Project, ParseError> {
+        let mut root = SourceDir::new("root".to_string());
+        for (path, content) in files {
+            let parts: Vec<&str> = path.split('/').collect();
+            let (name, dirs) = parts.split_last().unwrap();
+            let mut dir = &mut root;
+            for part in dirs {
+                dir = dir.subdir(part);
+            }
+            let source = format!("root/{}", path);
+            let entry = SourceEntry::File(source, Some(content.to_string()));
+            dir.entries.insert(name.to_string(), entry);
+        }
+        let mut project = Project::default();
+        scan_dir(&root, Vec::new(), None, &mut project)?;
+        Ok(project)
+    }
+
+    /// Parses the project from `entry`, returning what `reference` resolved to.
+    fn resolve_in(
+        files: &[(&str, &str)],
+        entry: &str,
+        reference: &str,
+    ) -> Result<String, ParseError> {
+        let entry: Vec<String> = entry.split('.').map(|x| x.to_string()).collect();
+        let mut report = ParseReport::default();
+        parse_project(scan_files(files)?, &[entry], &mut report)?;
+        let resolution = report
+            .resolutions
+            .iter()
+            .find(|r| r.reference.join(".") == reference)
+            .unwrap();
+        Ok(resolution.target.join("."))
+    }
+
+    /// Bodies with brackets nested in each other, empty, and inside splits.
+    const BRACKETED: [&str; 4] = [
+        "#((?!))(())!",
+        "##(!(?:!))((:)?)",
+        "#(@)(()(!?))",
+        "(#:(#@))?",
+    ];
+
+    /// Runs `body` as `_` with `input`, returning what it printed and the stack it left.
+    fn interpret_body(body: Vec<AST>, input: &[u8]) -> (Vec<u8>, Vec<bool>) {
+        let program = HashMap::from([(vec!["_".to_string()], body)]);
+        let mut output = Vec::new();
+        let finished = crate::interpreter::interpret_with_io(
+            &program,
+            vec!["_".to_string()],
+            &Default::default(),
+            &mut &input[..],
+            &mut output,
+            None,
+        )
+        .unwrap();
+        (output, finished.stack)
+    }
+
+    /// The error itself, without where it happened.
+    fn without_place(e: ParseError) -> ParseError {
+        match e {
+            ParseError::At(_, _, e) => without_place(*e),
+            e => e,
+        }
+    }
+
+    #[test]
+    fn print_before_any_function_is_an_error() {
+        let e = scan_files(&[("main.clink", "#\n_ ?!?????!")])
+            .err()
+            .unwrap();
+        match e {
+            ParseError::At(path, span, e) => {
+                assert_eq!(path, "root/main.clink");
+                assert_eq!(span, Span { line: 1, col: 1 });
+                assert!(matches!(*e, ParseError::UnexpectedToken(Token::Hash, _)));
+            }
+            e => panic!("expected an unexpected token, found {}", e),
+        }
+    }
+
+    /// Checks that scanning `content` fails for want of a package name after `!`, pointing at
+    /// `found` at `col` on the first line.
+    fn assert_bad_import(content: &str, found: Token, col: usize) {
+        match scan_files(&[("main.clink", content)]).err().unwrap() {
+            ParseError::At(_, span, e) => {
+                assert_eq!(span, Span { line: 1, col }, "{}", content);
+                match *e {
+                    ParseError::ExpectedPackageName('!', Some(token), _) => {
+                        assert_eq!(token.to_string(), found.to_string(), "{}", content)
+                    }
+                    e => panic!("expected a package name error, found {}", e),
+                }
+            }
+            e => panic!("expected an error with a place, found {}", e),
+        }
+    }
+
+    #[test]
+    fn import_of_an_import_is_an_error() {
+        assert_bad_import("! ! pkg", Token::Bang, 3);
+    }
+
+    #[test]
+    fn import_of_nothing_is_an_error() {
+        assert_bad_import("! ;", Token::Semicolon, 3);
+    }
+
+    #[test]
+    fn file_next_to_directory_of_the_same_name_conflicts() {
+        let root = std::env::temp_dir().join(format!("clink-conflict-{}", std::process::id()));
+        fs::create_dir_all(root.join("foo")).unwrap();
+        fs::write(root.join("foo.clink"), "A ?").unwrap();
+        fs::write(root.join("foo").join("bar.clink"), "B !").unwrap();
+
+        let result = scan(std::slice::from_ref(&root), &FileFilter::default());
+        fs::remove_dir_all(&root).unwrap();
+        match result.err().unwrap() {
+            ParseError::PackagePathConflict(a, b) => {
+                let mut paths = [a, b];
+                paths.sort();
+                let dir = root.join("foo").display().to_string();
+                let file = root.join("foo.clink").display().to_string();
+                assert_eq!(paths, [dir, file]);
+            }
+            e => panic!("expected a package path conflict, found {}", e),
+        }
+    }
+
+    #[test]
+    fn alias_stands_for_its_package() {
+        let files = [
+            ("lib/text/chars.clink", "A ?!?????!"),
+            ("main.clink", "c = lib.text.chars\n_ # c.A"),
+        ];
+        let found = resolve_in(&files, "main._", "c.A").unwrap();
+        assert_eq!(found, "lib.text.chars.A");
+    }
+
+    #[test]
+    fn alias_only_applies_in_its_file() {
+        let files = [
+            ("lib/text/chars.clink", "A ?!?????!"),
+            ("main.clink", "c = lib.text.chars\n!other\n_ # B"),
+            ("other.clink", "B c.A"),
+        ];
+        let e = resolve_in(&files, "main._", "c.A").unwrap_err();
+        assert!(matches!(without_place(e), ParseError::UnknownFunction(..)));
+    }
+
+    #[test]
+    fn import_of_a_nested_file() {
+        let files = [
+            ("lib/io/chars.clink", "A ?!?????!"),
+            ("main.clink", "!lib.io.chars\n_ # A"),
+        ];
+        assert_eq!(resolve_in(&files, "main._", "A").unwrap(), "lib.io.chars.A");
+    }
+
+    #[test]
+    fn same_package_beats_import() {
+        let files = [
+            ("chars.clink", "A ?!?????!"),
+            ("main.clink", "!chars\n_ # A;\nA ?!????!?"),
+        ];
+        assert_eq!(resolve_in(&files, "main._", "A").unwrap(), "main.A");
+    }
+
+    #[test]
+    fn enclosing_package_beats_import() {
+        let files = [
+            ("lib/util.clink", "A ?!?????!"),
+            ("x/util.clink", "A ?!????!?"),
+            ("x/main.clink", "!lib\n_ # util.A"),
+        ];
+        assert_eq!(
+            resolve_in(&files, "x.main._", "util.A").unwrap(),
+            "x.util.A"
+        );
+    }
+
+    #[test]
+    fn alias_beats_import() {
+        let files = [
+            ("lib/chars.clink", "A ?!?????!"),
+            ("other/c.clink", "A ?!????!?"),
+            ("main.clink", "c = lib.chars\n!other\n_ # c.A"),
+        ];
+        assert_eq!(resolve_in(&files, "main._", "c.A").unwrap(), "lib.chars.A");
+    }
+
+    #[test]
+    fn two_imports_with_the_same_name_are_ambiguous() {
+        let files = [
+            ("a.clink", "A ?!?????!"),
+            ("b.clink", "A ?!????!?"),
+            ("main.clink", "!a\n!b\n_ # A"),
+        ];
+        let e = resolve_in(&files, "main._", "A").unwrap_err();
+        match without_place(e) {
+            ParseError::AmbiguousReference(id, candidates) => {
+                assert_eq!(id, ["A"]);
+                assert_eq!(candidates, [["a", "A"], ["b", "A"]]);
+            }
+            e => panic!("expected an ambiguous reference, found {}", e),
+        }
+    }
+
+    #[test]
+    fn tie_among_imports_is_fine_if_an_earlier_tier_matches() {
+        let files = [
+            ("a.clink", "A ?!?????!"),
+            ("b.clink", "A ?!????!?"),
+            ("main.clink", "!a\n!b\n_ # A;\nA ?!????!!"),
+        ];
+        assert_eq!(resolve_in(&files, "main._", "A").unwrap(), "main.A");
+    }
+
+    #[test]
+    fn sibling_beats_cousin() {
+        let files = [
+            ("util.clink", "foo ?!?????!"),
+            ("x/util.clink", "foo ?!????!?"),
+            ("x/main.clink", "_ # util.foo"),
+        ];
+        let found = resolve_in(&files, "x.main._", "util.foo").unwrap();
+        assert_eq!(found, "x.util.foo");
+    }
+
+    #[test]
+    fn innermost_enclosing_package_wins() {
+        let files = [
+            ("x/util.clink", "foo ?!?????!"),
+            ("x/y/util.clink", "foo ?!????!?"),
+            ("x/y/main.clink", "_ # util.foo"),
+        ];
+        let found = resolve_in(&files, "x.y.main._", "util.foo").unwrap();
+        assert_eq!(found, "x.y.util.foo");
+    }
+
+    #[test]
+    fn full_name_beats_import() {
+        let files = [
+            ("util.clink", "foo ?!?????!"),
+            ("lib/util.clink", "foo ?!????!?"),
+            ("main.clink", "!lib\n_ # util.foo"),
+        ];
+        assert_eq!(
+            resolve_in(&files, "main._", "util.foo").unwrap(),
+            "util.foo"
+        );
+    }
+
+    #[test]
+    fn canonicalize_is_idempotent() {
+        for body in BRACKETED {
+            let once = canonicalize(parse_body(body).unwrap());
+            assert_eq!(canonicalize(once.clone()), once, "{}", body);
+        }
+    }
+
+    #[test]
+    fn canonicalize_leaves_no_brackets() {
+        fn has_brackets(asts: &[AST]) -> bool {
+            asts.iter().any(|ast| match ast {
+                AST::Bracketed(_) => true,
+                AST::Split(l, r) => has_brackets(l) || has_brackets(r),
+                _ => false,
+            })
+        }
+        for body in BRACKETED {
+            assert!(has_brackets(&parse_body(body).unwrap()), "{}", body);
+            assert!(
+                !has_brackets(&canonicalize(parse_body(body).unwrap())),
+                "{}",
+                body
+            );
+        }
+    }
+
+    #[test]
+    fn canonicalize_runs_the_same() {
+        for body in BRACKETED {
+            let asts = parse_body(body).unwrap();
+            let canonical = canonicalize(asts.clone());
+            for input in [&b""[..], b"a", b"\xff\x00"] {
+                assert_eq!(
+                    interpret_body(canonical.clone(), input),
+                    interpret_body(asts.clone(), input),
+                    "{}",
+                    body
+                );
+            }
+        }
+    }
+
+    /// Parses the project from `main._`.
+    fn parse_files(files: &[(&str, &str)]) -> Result<HashMap<Vec<String>, Vec<AST>>, ParseError> {
+        let entry = vec!["main".to_string(), "_".to_string()];
+        parse_project(scan_files(files)?, &[entry], &mut ParseReport::default())
+    }
+
+    #[test]
+    fn tail_calls_can_end_either_branch() {
+        let files = [("main.clink", "_ ^f:(^g ?);\nf ?;\ng !")];
+        let program = parse_files(&files).unwrap();
+        let call = |f: &str| AST::TailCall(vec!["main".to_string(), f.to_string()]);
+        assert_eq!(
+            program[&vec!["main".to_string(), "_".to_string()]],
+            [AST::Split(vec![call("f")], vec![AST::Right, call("g")])]
+        );
+    }
+
+    #[test]
+    fn tail_call_with_more_after_it_is_an_error() {
+        for (body, col) in [("_ ? ^f", 5), ("_ ? (^f : !)", 6), ("_ ^f:? ^f", 8)] {
+            let files = [("main.clink", &format!("{};\nf !", body)[..])];
+            match parse_files(&files).err().unwrap() {
+                ParseError::At(path, span, e) => {
+                    assert_eq!(path, "root/main.clink");
+                    assert_eq!(span, Span { line: 1, col }, "{}", body);
+                    assert!(matches!(*e, ParseError::TailCallNotLast(_)));
+                }
+                e => panic!("expected a misplaced tail call, found {}", e),
+            }
+        }
+    }
+}
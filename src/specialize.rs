@@ -0,0 +1,269 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::{analysis::call_graph, parser::AST};
+
+/// How many primitives to evaluate before giving up, so a program that loops without needing any
+/// more input still specialises.
+const FUEL: usize = 1_000_000;
+
+/// Specialises `entry` to a fixed prefix of its input, returning a program whose entry behaves
+/// like the original would after reading `input`.
+///
+/// Evaluation follows the interpreter for as long as every split's condition is known, replacing
+/// reads of `input` with the bits they would push and splits with the branch they would take. It
+/// stops at the first split on unread input, and the rest of the program is left as it is.
+pub fn specialize(
+    program: &HashMap<Vec<String>, Vec<AST>>,
+    entry: &Vec<String>,
+    input: &[u8],
+) -> Option<HashMap<Vec<String>, Vec<AST>>> {
+    let mut frames: Vec<&[AST]> = vec![program.get(entry)?];
+    let mut input = input.iter();
+    // mirrors the stack at run time, with `None` for bits that depend on unread input
+    let mut stack: Vec<Option<bool>> = Vec::new();
+    let mut residual = Vec::new();
+    let mut fuel = FUEL;
+
+    while let Some(frame) = frames.last_mut() {
+        let (ast, rest) = match frame.split_first() {
+            Some(x) => x,
+            None => {
+                frames.pop();
+                continue;
+            }
+        };
+        if fuel == 0 {
+            break;
+        }
+        fuel -= 1;
+
+        match ast {
+            AST::Left | AST::Right => {
+                stack.push(Some(matches!(ast, AST::Left)));
+                residual.push(ast.clone());
+            }
+            AST::Print => {
+                for _ in 0..8 {
+                    stack.pop();
+                }
+                residual.push(AST::Print);
+            }
+            AST::Read => match input.next() {
+                Some(byte) => {
+                    for i in 0..8 {
+                        let bit = (byte >> i) & 1 == 1;
+                        stack.push(Some(bit));
+                        residual.push(if bit { AST::Left } else { AST::Right });
+                    }
+                }
+                None => {
+                    stack.extend([None; 8]);
+                    residual.push(AST::Read);
+                }
+            },
+            AST::Split(l, r) => match stack.last().copied().unwrap_or(Some(false)) {
+                Some(condition) => {
+                    stack.pop();
+                    // drop the push the condition came from rather than popping it at run time
+                    if let Some(AST::Left | AST::Right) = residual.last() {
+                        residual.pop();
+                    } else {
+                        residual.push(AST::Split(Vec::new(), Vec::new()));
+                    }
+                    *frame = rest;
+                    frames.push(if condition { l } else { r });
+                    continue;
+                }
+                None => break,
+            },
+            AST::Bracketed(asts) => {
+                *frame = rest;
+                frames.push(asts);
+                continue;
+            }
+            AST::Id(id) | AST::TailCall(id) => match program.get(id) {
+                Some(asts) => {
+                    *frame = rest;
+                    frames.push(asts);
+                    continue;
+                }
+                None => break,
+            },
+        }
+        *frame = rest;
+    }
+
+    // whatever wasn't evaluated runs as it is, innermost first
+    while let Some(frame) = frames.pop() {
+        residual.extend(frame.iter().cloned());
+    }
+
+    // the residual can still call the original entry, which has to keep its old body
+    let mut original = entry.clone();
+    if let Some(last) = original.last_mut() {
+        last.push('\'');
+    }
+
+    let mut specialized = HashMap::new();
+    let graph = call_graph(program);
+    let mut seen = HashSet::new();
+    let mut to_visit = calls(&residual);
+    while let Some(id) = to_visit.pop() {
+        if seen.insert(id.clone()) {
+            to_visit.extend(graph.get(&id).cloned().unwrap_or_default());
+        }
+    }
+    for id in seen {
+        if let Some(asts) = program.get(&id) {
+            let name = if &id == entry { original.clone() } else { id };
+            specialized.insert(name, rename(asts, entry, &original));
+        }
+    }
+    specialized.insert(entry.clone(), rename(&residual, entry, &original));
+
+    Some(specialized)
+}
+
+fn calls(asts: &Vec<AST>) -> Vec<Vec<String>> {
+    let mut ids = Vec::new();
+    for ast in asts {
+        match ast {
+            AST::Split(l, r) => {
+                ids.extend(calls(l));
+                ids.extend(calls(r));
+            }
+            AST::Bracketed(asts) => ids.extend(calls(asts)),
+            AST::Id(id) | AST::TailCall(id) => ids.push(id.clone()),
+            _ => {}
+        }
+    }
+    ids
+}
+
+fn rename(asts: &Vec<AST>, from: &Vec<String>, to: &Vec<String>) -> Vec<AST> {
+    asts.iter()
+        .map(|ast| match ast {
+            AST::Split(l, r) => AST::Split(rename(l, from, to), rename(r, from, to)),
+            AST::Bracketed(asts) => AST::Bracketed(rename(asts, from, to)),
+            AST::Id(id) if id == from => AST::Id(to.clone()),
+            AST::TailCall(id) if id == from => AST::TailCall(to.clone()),
+            ast => ast.clone(),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{interpreter::interpret_with_io, parser::parse_body};
+
+    fn name(f: &str) -> Vec<String> {
+        vec![f.to_string()]
+    }
+
+    /// Runs `_` in `program` on `input`, returning what it printed and the stack it finished with.
+    fn run(program: &HashMap<Vec<String>, Vec<AST>>, input: &[u8]) -> (Vec<u8>, Vec<bool>) {
+        let mut output = Vec::new();
+        let finished = interpret_with_io(
+            program,
+            name("_"),
+            &Default::default(),
+            &mut &input[..],
+            &mut output,
+            None,
+        )
+        .unwrap();
+        (output, finished.stack)
+    }
+
+    /// Echoes bytes up to and including the first without its top bit set.
+    fn echo() -> Vec<AST> {
+        vec![
+            AST::Read,
+            AST::Split(
+                vec![AST::Left, AST::Print, AST::TailCall(name("_"))],
+                vec![AST::Right, AST::Print],
+            ),
+        ]
+    }
+
+    /// Specialises `_` to every prefix of `input` in turn, checking each does on the rest of the
+    /// input what the original does on all of it.
+    fn agrees(program: &HashMap<Vec<String>, Vec<AST>>, input: &[u8]) {
+        let expected = run(program, input);
+        for i in 0..=input.len() {
+            let specialized = specialize(program, &name("_"), &input[..i]).unwrap();
+            assert_eq!(
+                run(&specialized, &input[i..]),
+                expected,
+                "fixing {} bytes",
+                i
+            );
+        }
+    }
+
+    #[test]
+    fn split_on_read_bit_agrees() {
+        let program = HashMap::from([(name("_"), parse_body("#(!!!!!!!!:????????)@").unwrap())]);
+        agrees(&program, &[0x80]);
+        agrees(&program, &[0x7f]);
+    }
+
+    #[test]
+    fn split_on_empty_stack_agrees() {
+        let program = HashMap::from([(name("_"), parse_body("#@#(!!!!!!!!:????????)").unwrap())]);
+        agrees(&program, b"x");
+    }
+
+    #[test]
+    fn recursive_call_agrees() {
+        let program = HashMap::from([(name("_"), echo())]);
+        agrees(&program, &[0xe1, 0xe2, b'c', b'd']);
+    }
+
+    #[test]
+    fn known_condition_drops_its_push() {
+        let program = HashMap::from([(name("_"), parse_body("(!:?)!").unwrap())]);
+        let specialized = specialize(&program, &name("_"), &[]).unwrap();
+        assert_eq!(specialized[&name("_")], [AST::Left]);
+
+        // the condition wasn't pushed last, so it has to be popped by a split with empty branches
+        let program = HashMap::from([(name("_"), parse_body("(!:?)#!!!!!!!!!").unwrap())]);
+        let specialized = specialize(&program, &name("_"), &[]).unwrap();
+        let mut expected = vec![AST::Left; 9];
+        expected.extend([AST::Print, AST::Split(Vec::new(), Vec::new()), AST::Left]);
+        assert_eq!(specialized[&name("_")], expected);
+    }
+
+    #[test]
+    fn running_out_of_fuel_leaves_runnable_residual() {
+        // `t0` pushes a bit and splits it off again, and each `t` after it runs the one before
+        // twice, so `t19` takes over a million steps without needing any input
+        let mut program = HashMap::from([(
+            name("t0"),
+            vec![AST::Left, AST::Split(Vec::new(), Vec::new())],
+        )]);
+        for i in 1..20 {
+            let call = AST::Id(name(&format!("t{}", i - 1)));
+            program.insert(name(&format!("t{}", i)), vec![call.clone(), call]);
+        }
+        let mut entry = vec![AST::Id(name("t19"))];
+        entry.extend(parse_body("#!?!?????!").unwrap());
+        program.insert(name("_"), entry);
+
+        let specialized = specialize(&program, &name("_"), &[]).unwrap();
+        assert!(!calls(&specialized[&name("_")]).is_empty());
+        assert_eq!(run(&specialized, &[]), run(&program, &[]));
+    }
+
+    #[test]
+    fn calls_to_entry_go_to_its_copy() {
+        let program = HashMap::from([(name("_"), echo())]);
+        let specialized = specialize(&program, &name("_"), &[0xe1]).unwrap();
+        assert_eq!(calls(&specialized[&name("_")]), [name("_'")]);
+        assert_eq!(
+            specialized[&name("_'")],
+            rename(&echo(), &name("_"), &name("_'"))
+        );
+    }
+}
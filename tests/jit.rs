@@ -0,0 +1,176 @@
+//! Compares programs run with the JIT against the interpreter. A JIT-compiled program reads and
+//! prints through its process's stdin and stdout, so each run is a `clink` process of its own.
+
+use std::{
+    env, fs,
+    io::Write,
+    path::PathBuf,
+    process::{Command, Stdio},
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+/// A directory holding one program, removed once it's dropped.
+struct Scratch(PathBuf);
+
+impl Scratch {
+    fn new(body: &str) -> Scratch {
+        static NEXT: AtomicUsize = AtomicUsize::new(0);
+        let name = format!(
+            "clink-jit-{}-{}",
+            std::process::id(),
+            NEXT.fetch_add(1, Ordering::Relaxed)
+        );
+        let dir = env::temp_dir().join(name);
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("main.clink"), format!("_ {}\n", body)).unwrap();
+        Scratch(dir)
+    }
+}
+
+impl Drop for Scratch {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+/// Runs `_` with the given body through `clink run` with `options`, returning what it printed
+/// given `input`. It has to exit with 0.
+fn run(body: &str, input: &[u8], options: &[&str]) -> Vec<u8> {
+    let scratch = Scratch::new(body);
+    let mut child = Command::new(env!("CARGO_BIN_EXE_clink"))
+        .arg("run")
+        .args(options)
+        .arg("main.clink")
+        .current_dir(&scratch.0)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child.stdin.take().unwrap().write_all(input).unwrap();
+    let output = child.wait_with_output().unwrap();
+    assert!(
+        output.status.success(),
+        "`{}` with {:?} failed: {}",
+        body,
+        options,
+        String::from_utf8_lossy(&output.stderr)
+    );
+    output.stdout
+}
+
+fn interpreted(body: &str, input: &[u8]) -> Vec<u8> {
+    run(body, input, &[])
+}
+
+fn jitted(body: &str, input: &[u8]) -> Vec<u8> {
+    run(body, input, &["--compiled"])
+}
+
+/// A xorshift generator, so any program it makes can be made again from its seed.
+struct Rng(u64);
+
+impl Rng {
+    fn below(&mut self, n: u64) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0 % n
+    }
+}
+
+/// A random body of up to `size` nodes, with splits and brackets nested up to `depth` deep, and
+/// the most bits it can push, since with no calls each node runs at most once.
+fn random_body(rng: &mut Rng, size: u64, depth: u32) -> (String, usize) {
+    let mut body = String::new();
+    let mut pushed = 0;
+    for _ in 0..rng.below(size + 1) {
+        match rng.below(if depth == 0 { 8 } else { 10 }) {
+            0..=2 => {
+                body += "!";
+                pushed += 1;
+            }
+            3..=5 => {
+                body += "?";
+                pushed += 1;
+            }
+            6 => body += "#",
+            7 => {
+                body += "@";
+                pushed += 8;
+            }
+            8 => {
+                let (l, l_pushed) = random_body(rng, size / 2, depth - 1);
+                let (r, r_pushed) = random_body(rng, size / 2, depth - 1);
+                body += &format!("({}:{})", l, r);
+                pushed += l_pushed + r_pushed;
+            }
+            _ => {
+                let (inner, inner_pushed) = random_body(rng, size / 2, depth - 1);
+                body += &format!("({})", inner);
+                pushed += inner_pushed;
+            }
+        }
+    }
+    (body, pushed)
+}
+
+/// Runs random programs on random input in both backends, which have to print the same and
+/// leave the same stack. Set `CLINK_SEED` to run just the program from a failure.
+#[test]
+fn random_programs_agree_with_interpreter() {
+    let seeds = match env::var("CLINK_SEED") {
+        Ok(seed) => vec![seed.parse().unwrap()],
+        Err(_) => (1..=100).collect(),
+    };
+    // xorshift never leaves 0, so that seed would only ever make an empty program
+    assert!(!seeds.contains(&0), "`CLINK_SEED` can't be 0");
+    for seed in seeds {
+        let mut rng = Rng(seed);
+        let (body, pushed) = random_body(&mut rng, 24, 3);
+        // print whatever is left, and the `?`s below it, last, so the stacks get compared too
+        let body = "#".repeat(pushed / 8 + 1) + &body;
+        let input: Vec<u8> = (0..rng.below(4)).map(|_| rng.below(256) as u8).collect();
+
+        assert_eq!(
+            jitted(&body, &input),
+            interpreted(&body, &input),
+            "seed {} ran `{}` on {:?} differently",
+            seed,
+            body,
+            input
+        );
+    }
+}
+
+#[test]
+fn empty_entry_prints_nothing() {
+    // `run` checks it exits with 0
+    assert_eq!(interpreted("", b""), b"");
+    assert_eq!(jitted("", b""), b"");
+}
+
+#[test]
+fn print_agrees_with_interpreter() {
+    // 0xe9 then 'A', written most significant bit first
+    let body = "## !!!?!??!?!?????!";
+    assert_eq!(interpreted(body, b""), [0xe9, b'A']);
+    assert_eq!(jitted(body, b""), [0xe9, b'A']);
+}
+
+#[test]
+fn echo_agrees_with_interpreter() {
+    let input = [0xff, 0x80, b'\n'];
+    assert_eq!(interpreted("#@#@#@", &input), input);
+    assert_eq!(jitted("#@#@#@", &input), input);
+}
+
+#[test]
+fn stack_holds_as_many_bits_as_its_size() {
+    let body = "# !?!?!??!";
+    for debug_stack in [&[][..], &["--debug-stack"]] {
+        let options = [&["--compiled", "--stack-size", "8"], debug_stack].concat();
+        assert_eq!(run(body, b"", &options), interpreted(body, b""));
+    }
+}